@@ -0,0 +1,89 @@
+//! Errors which are categorized by what failed.
+
+use std::fmt;
+
+/// The kind of an [Error], so that failures can be grouped, like in the
+/// summary of `--keep-going`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// Failed to download an archive.
+    Download,
+    /// An archive is corrupt, or doesn't match its checksum.
+    Decode,
+    /// A git command failed.
+    Git,
+    /// Running tokei or parsing its output failed.
+    Tokei,
+    /// Failed to read or write files.
+    Io,
+    /// Failed to post an output.
+    Post,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Download => write!(f, "download"),
+            ErrorKind::Decode => write!(f, "corrupt archive"),
+            ErrorKind::Git => write!(f, "git"),
+            ErrorKind::Tokei => write!(f, "tokei"),
+            ErrorKind::Io => write!(f, "io"),
+            ErrorKind::Post => write!(f, "post"),
+        }
+    }
+}
+
+/// An error when downloading or analyzing a kernel, categorized by what
+/// failed.
+///
+/// Each variant displays exactly like the error it wraps.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Failed to download an archive.
+    #[error(transparent)]
+    Download(anyhow::Error),
+    /// An archive is corrupt, or doesn't match its checksum.
+    #[error(transparent)]
+    Decode(anyhow::Error),
+    /// A git command failed.
+    #[error(transparent)]
+    Git(anyhow::Error),
+    /// Running tokei or parsing its output failed.
+    #[error(transparent)]
+    Tokei(anyhow::Error),
+    /// Failed to read or write files.
+    #[error(transparent)]
+    Io(anyhow::Error),
+    /// Failed to post an output.
+    #[error(transparent)]
+    Post(anyhow::Error),
+}
+
+impl Error {
+    /// Get the kind of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Download(..) => ErrorKind::Download,
+            Error::Decode(..) => ErrorKind::Decode,
+            Error::Git(..) => ErrorKind::Git,
+            Error::Tokei(..) => ErrorKind::Tokei,
+            Error::Io(..) => ErrorKind::Io,
+            Error::Post(..) => ErrorKind::Post,
+        }
+    }
+
+    /// Wrap the error with additional context, keeping its kind.
+    pub fn context<C>(self, context: C) -> Error
+    where
+        C: fmt::Display + Send + Sync + 'static,
+    {
+        match self {
+            Error::Download(e) => Error::Download(e.context(context)),
+            Error::Decode(e) => Error::Decode(e.context(context)),
+            Error::Git(e) => Error::Git(e.context(context)),
+            Error::Tokei(e) => Error::Tokei(e.context(context)),
+            Error::Io(e) => Error::Io(e.context(context)),
+            Error::Post(e) => Error::Post(e.context(context)),
+        }
+    }
+}
@@ -1,25 +1,112 @@
+use crate::version::Version;
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use lazy_static::lazy_static;
+use log::debug;
+use std::cmp;
+use std::collections::HashSet;
+use std::env;
 use std::ffi::OsStr;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Serializes changes to worktrees, since git doesn't support concurrently
+    /// adding and pruning them in the same repository.
+    static ref WORKTREE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Log the full command line of a command which is about to run.
+pub fn log_command(command: &process::Command) {
+    debug!("running: {:?}", command);
+}
+
+/// Make the given path absolute, since git is run inside of the repository.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_owned());
+    }
+
+    let dir = env::current_dir().map_err(|e| anyhow!("failed to get current dir: {}", e))?;
+    Ok(dir.join(path))
+}
+
+/// Sort the given tags by their version.
+///
+/// Release candidates are sorted immediately before the release they belong
+/// to. Tags which aren't versions are sorted last, in their original order.
+pub fn sort_semver(tags: Vec<String>) -> Vec<String> {
+    let mut tags = tags
+        .into_iter()
+        .map(|tag| (Version::parse(&tag), tag))
+        .collect::<Vec<_>>();
+
+    tags.sort_by(|a, b| match (&a.0, &b.0) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (Some(_), None) => cmp::Ordering::Less,
+        (None, Some(_)) => cmp::Ordering::Greater,
+        (None, None) => cmp::Ordering::Equal,
+    });
+
+    tags.into_iter().map(|(_, tag)| tag).collect()
+}
 
 /// Interact with a git repository.
 #[derive(Debug, Clone, Copy)]
 pub struct Git<'a> {
     pub repo: &'a Path,
+    /// Configuration passed to every invocation of git, as `key=value`.
+    pub config: &'a [String],
 }
 
 impl<'a> Git<'a> {
     pub fn new(repo: &'a Path) -> Git {
-        Git { repo }
+        Git { repo, config: &[] }
+    }
+
+    /// Pass the given configuration to every invocation of git, as with
+    /// `git -c key=value`.
+    pub fn with_config(self, config: &'a [String]) -> Git<'a> {
+        Git { config, ..self }
+    }
+
+    /// Interact with the worktree at the given path, using the same
+    /// configuration as this repository.
+    pub fn worktree<'b>(&self, path: &'b Path) -> Git<'b>
+    where
+        'a: 'b,
+    {
+        Git {
+            repo: path,
+            config: self.config,
+        }
+    }
+
+    /// Build a git command which runs in the repository.
+    ///
+    /// The environment is inherited, so variables like `GIT_SSH_COMMAND` and
+    /// credential helpers configured by the user apply.
+    fn command(&self) -> process::Command {
+        let mut command = process::Command::new("git");
+
+        for config in self.config {
+            command.arg("-c").arg(config);
+        }
+
+        command.current_dir(&self.repo);
+        command
     }
 
     /// Call git with the given arguments inheriting stdout.
     fn git_run<S: AsRef<OsStr>>(&self, args: impl IntoIterator<Item = S>) -> Result<()> {
-        let status = process::Command::new("git")
-            .current_dir(&self.repo)
-            .args(args)
+        let mut command = self.command();
+        command.args(args);
+        log_command(&command);
+
+        let status = command
             .status()
             .map_err(|e| anyhow!("git: failed to call: {}", e))?;
 
@@ -32,9 +119,11 @@ impl<'a> Git<'a> {
 
     /// Call git with the given arguments.
     fn git<S: AsRef<OsStr>>(&self, args: impl IntoIterator<Item = S>) -> Result<String> {
-        let out = process::Command::new("git")
-            .current_dir(&self.repo)
-            .args(args)
+        let mut command = self.command();
+        command.args(args);
+        log_command(&command);
+
+        let out = command
             .output()
             .map_err(|e| anyhow!("git: failed to call: {}", e))?;
 
@@ -49,6 +138,169 @@ impl<'a> Git<'a> {
         Ok(out.to_string())
     }
 
+    /// Call git with the given arguments and capture its output.
+    ///
+    /// This is intended for read-only queries. Errors include anything git
+    /// wrote to stderr.
+    pub fn run_capture<S: AsRef<OsStr>>(
+        &self,
+        args: impl IntoIterator<Item = S>,
+    ) -> Result<String> {
+        self.git(args)
+    }
+
+    /// Describe the given reference in terms of the closest tag, like
+    /// `v6.6-rc1-12-g1234abcd`.
+    pub fn describe(&self, reference: &str) -> Result<String> {
+        let out = self.git(&["describe", "--tags", reference])?;
+        Ok(out.trim().to_string())
+    }
+
+    /// List the references matching the given pattern, as pairs of the hash
+    /// and the full name of each reference.
+    pub fn show_ref(&self, reference: &str) -> Result<Vec<(String, String)>> {
+        let out = self.git(&["show-ref", reference])?;
+
+        out.lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let mut it = line.splitn(2, ' ');
+
+                match (it.next(), it.next()) {
+                    (Some(hash), Some(name)) => Ok((hash.to_string(), name.to_string())),
+                    _ => Err(anyhow!("bad show-ref output: {}", line)),
+                }
+            })
+            .collect()
+    }
+
+    /// Initialize an empty repository, creating the directory if needed.
+    pub fn init(&self) -> Result<()> {
+        fs::create_dir_all(self.repo).map_err(|e| {
+            anyhow!(
+                "failed to create repository: {}: {}",
+                self.repo.display(),
+                e
+            )
+        })?;
+
+        self.git_run(&["init", "--quiet"])
+    }
+
+    /// Shallowly fetch all tags from the given remote.
+    pub fn fetch(&self, remote: &str) -> Result<()> {
+        self.git_run(&["fetch", "--depth", "1", "--tags", remote])
+    }
+
+    /// Shallowly fetch a single tag from the given remote, so that only the
+    /// tree of that tag is downloaded.
+    pub fn fetch_tag(&self, remote: &str, tag: &str) -> Result<()> {
+        let refspec = format!("refs/tags/{0}:refs/tags/{0}", tag);
+        self.git_run(&[
+            "fetch",
+            "--quiet",
+            "--depth",
+            "1",
+            "--no-tags",
+            remote,
+            &refspec,
+        ])
+    }
+
+    /// Test if the given tag exists locally.
+    pub fn has_tag(&self, tag: &str) -> Result<bool> {
+        let status = self
+            .command()
+            .args(&["rev-parse", "--verify", "--quiet"])
+            .arg(format!("refs/tags/{}", tag))
+            .stdout(process::Stdio::null())
+            .status()
+            .map_err(|e| anyhow!("git: failed to call: {}", e))?;
+
+        Ok(status.success())
+    }
+
+    /// Test if `ancestor` is an ancestor of `descendant`, or the same commit.
+    pub fn is_ancestor(&self, ancestor: &str, descendant: &str) -> Result<bool> {
+        let status = self
+            .command()
+            .args(&["merge-base", "--is-ancestor", ancestor, descendant])
+            .status()
+            .map_err(|e| anyhow!("git: failed to call: {}", e))?;
+
+        // NB: exits with 1 if it isn't an ancestor, and anything else on
+        // errors.
+        match status.code() {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(anyhow!(
+                "git: failed to check ancestry: {}..{}: {}",
+                ancestor,
+                descendant,
+                status
+            )),
+        }
+    }
+
+    /// List the tags of the given remote, without fetching anything.
+    pub fn remote_tags(&self, remote: &str) -> Result<Vec<String>> {
+        let refs = self.ls_remote(&["--tags", "--refs", remote])?;
+
+        Ok(refs
+            .into_iter()
+            .filter_map(|(name, _)| name.strip_prefix("refs/tags/").map(String::from))
+            .collect())
+    }
+
+    /// List the branches of the given remote matching the given pattern, as
+    /// pairs of the branch name and the commit at its head, without fetching
+    /// anything.
+    ///
+    /// The remote `.` lists the branches of this repository.
+    pub fn remote_branches(&self, remote: &str, pattern: &str) -> Result<Vec<(String, String)>> {
+        let refs = self.ls_remote(&["--heads", remote, pattern])?;
+
+        Ok(refs
+            .into_iter()
+            .filter_map(|(name, commit)| {
+                let branch = name.strip_prefix("refs/heads/")?;
+                Some((branch.to_string(), commit))
+            })
+            .collect())
+    }
+
+    /// Call `git ls-remote` with the given arguments, and get the listed
+    /// references as pairs of the reference name and the object it points to.
+    fn ls_remote(&self, args: &[&str]) -> Result<Vec<(String, String)>> {
+        let mut command = vec!["ls-remote"];
+        command.extend(args);
+        let out = self.git(&command)?;
+
+        Ok(out
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split('\t');
+                let object = parts.next()?;
+                let name = parts.next()?;
+                Some((name.to_string(), object.to_string()))
+            })
+            .collect())
+    }
+
+    /// Shallowly fetch the head of the given branch from the given remote.
+    pub fn fetch_branch(&self, remote: &str, branch: &str) -> Result<()> {
+        let refspec = format!("refs/heads/{}", branch);
+        self.git_run(&[
+            "fetch",
+            "--quiet",
+            "--depth",
+            "1",
+            "--no-tags",
+            remote,
+            &refspec,
+        ])
+    }
+
     /// Get all git tags, sorted by commiter date.
     pub fn tags(&self) -> Result<Vec<String>> {
         let out = self.git(&["tag", "--sort=taggerdate"])?;
@@ -59,10 +311,134 @@ impl<'a> Git<'a> {
             .collect())
     }
 
-    pub fn checkout_hard(&self, reference: &str) -> Result<()> {
-        self.git_run(&["reset", "--hard", "HEAD"])?;
-        self.git_run(&["clean", "-fdx"])?;
-        self.git_run(&["checkout", reference])?;
-        Ok(())
+    /// Get all git tags, sorted by their version.
+    ///
+    /// Release candidates are sorted immediately before the release they belong
+    /// to. Tags which aren't versions are sorted last, by tagger date.
+    pub fn tags_sorted_semver(&self) -> Result<Vec<String>> {
+        Ok(sort_semver(self.tags()?))
+    }
+
+    /// Resolve the given reference to the hash of the commit it points to.
+    pub fn rev_parse(&self, reference: &str) -> Result<String> {
+        let out = self.git(&["rev-parse", &format!("{}^{{commit}}", reference)])?;
+        Ok(out.trim().to_string())
+    }
+
+    /// Test if the repository is shallow, in which case its history is
+    /// incomplete.
+    pub fn is_shallow(&self) -> Result<bool> {
+        let out = self.git(&["rev-parse", "--is-shallow-repository"])?;
+        Ok(out.trim() == "true")
+    }
+
+    /// Count the number of commits reachable from `to` but not from `from`.
+    pub fn rev_list_count(&self, from: &str, to: &str) -> Result<u64> {
+        let out = self.git(&["rev-list", "--count", &format!("{}..{}", from, to)])?;
+        let out = out.trim();
+
+        out.parse()
+            .map_err(|e| anyhow!("bad commit count: {}..{}: {}: {}", from, to, out, e))
+    }
+
+    /// List the files which changed between `from` and `to`, relative to the
+    /// root of the repository.
+    pub fn changed_files(&self, from: &str, to: &str) -> Result<HashSet<PathBuf>> {
+        let out = self.git(&["diff", "--name-only", &format!("{}..{}", from, to)])?;
+        Ok(out.lines().map(PathBuf::from).collect())
+    }
+
+    /// Get the commit date of the given reference.
+    pub fn tag_date(&self, reference: &str) -> Result<DateTime<FixedOffset>> {
+        let out = self.git(&["log", "-1", "--format=%cI", reference])?;
+        let out = out.trim();
+
+        DateTime::parse_from_rfc3339(out)
+            .map_err(|e| anyhow!("bad date for reference: {}: {}: {}", reference, out, e))
+    }
+
+    /// Check out the given reference into a new worktree at `path`.
+    ///
+    /// This leaves the working tree of the repository itself untouched.
+    pub fn add_worktree(&self, reference: &str, path: &Path) -> Result<()> {
+        let path = absolute(path)?;
+        let _guard = WORKTREE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // NB: clean up worktrees whose directories were removed by hand.
+        self.git_run(&["worktree", "prune"])?;
+        self.git_run(&[
+            OsStr::new("worktree"),
+            OsStr::new("add"),
+            OsStr::new("--quiet"),
+            OsStr::new("--detach"),
+            path.as_os_str(),
+            OsStr::new(reference),
+        ])
+    }
+
+    /// Check out the given reference into a new worktree at `path`, with
+    /// only the given directories present.
+    ///
+    /// Files in the root of the tree are always present, as per the cone mode
+    /// of sparse checkouts.
+    pub fn add_sparse_worktree(
+        &self,
+        reference: &str,
+        path: &Path,
+        paths: &[String],
+    ) -> Result<()> {
+        let path = absolute(path)?;
+        let _guard = WORKTREE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        self.git_run(&["worktree", "prune"])?;
+        self.git_run(&[
+            OsStr::new("worktree"),
+            OsStr::new("add"),
+            OsStr::new("--quiet"),
+            OsStr::new("--detach"),
+            OsStr::new("--no-checkout"),
+            path.as_os_str(),
+            OsStr::new(reference),
+        ])?;
+
+        let worktree = self.worktree(&path);
+        worktree.sparse_checkout(paths)?;
+        worktree.git_run(&["checkout", "--quiet", "--detach", reference])
+    }
+
+    /// Restrict the working tree to the given directories, using a sparse
+    /// checkout in cone mode.
+    pub fn sparse_checkout(&self, paths: &[String]) -> Result<()> {
+        let mut args = vec!["sparse-checkout", "set", "--cone", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        self.git_run(&args)
+    }
+
+    /// Check out the given reference in this working tree, only updating the
+    /// files which differ from the current checkout.
+    pub fn checkout(&self, reference: &str) -> Result<()> {
+        self.git_run(&["checkout", "--quiet", "--detach", reference])
+    }
+
+    /// Test if the working tree has changes or untracked files.
+    pub fn is_dirty(&self) -> Result<bool> {
+        let out = self.git(&["status", "--porcelain", "--ignored"])?;
+        Ok(!out.trim().is_empty())
+    }
+
+    /// Discard all changes and untracked files in the working tree.
+    pub fn clean(&self) -> Result<()> {
+        self.git_run(&["reset", "--quiet", "--hard"])?;
+        self.git_run(&["clean", "--quiet", "-fdx"])
+    }
+
+    /// Remove the worktree at `path`, discarding any changes in it.
+    pub fn remove_worktree(&self, path: &Path) -> Result<()> {
+        let path = absolute(path)?;
+        let _guard = WORKTREE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        self.git_run(&[
+            OsStr::new("worktree"),
+            OsStr::new("remove"),
+            OsStr::new("--force"),
+            path.as_os_str(),
+        ])
     }
 }
@@ -0,0 +1,128 @@
+//! Rendering statistics as a standalone HTML page.
+
+use crate::stats::{LanguageStats, Output};
+use std::fmt::Write as _;
+
+/// The template of the page, with `{{name}}` placeholders.
+const TEMPLATE: &str = include_str!("report.html");
+
+/// Width of the area that bars are drawn in, in pixels.
+const BAR_WIDTH: u64 = 600;
+/// Width of the labels to the left of the bars, in pixels.
+const LABEL_WIDTH: u64 = 160;
+/// Height of a single bar, including the space around it, in pixels.
+const BAR_HEIGHT: u64 = 20;
+
+/// Escape text to be included in HTML.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Render a bar chart of the lines of code of each language.
+fn chart(languages: &[(&String, &LanguageStats)]) -> String {
+    let max = languages.iter().map(|(_, s)| s.code).max().unwrap_or(0);
+    let height = languages.len() as u64 * BAR_HEIGHT;
+
+    let mut svg = String::new();
+
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        LABEL_WIDTH + BAR_WIDTH + 80,
+        height
+    );
+
+    for (i, (language, stats)) in languages.iter().enumerate() {
+        let y = i as u64 * BAR_HEIGHT;
+
+        let width = match max {
+            0 => 0,
+            max => stats.code * BAR_WIDTH / max,
+        };
+
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>",
+            LABEL_WIDTH - 8,
+            y + 14,
+            escape(language)
+        );
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#4a7ab5\"/>",
+            LABEL_WIDTH,
+            y + 2,
+            width,
+            BAR_HEIGHT - 4
+        );
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\">{}</text>",
+            LABEL_WIDTH + width + 4,
+            y + 14,
+            stats.code
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Render the given output as a standalone HTML page, with a table of the
+/// statistics of each language and a bar chart of their lines of code.
+///
+/// The page doesn't depend on any external assets.
+pub fn render(output: &Output) -> String {
+    let mut languages = output.all.iter().collect::<Vec<_>>();
+    languages.sort_by(|a, b| b.1.code.cmp(&a.1.code).then_with(|| a.0.cmp(b.0)));
+
+    let mut rows = String::new();
+
+    for (language, stats) in &languages {
+        let _ = writeln!(
+            rows,
+            "<tr><td>{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td><td class=\"n\">{}</td></tr>",
+            escape(language),
+            stats.files,
+            stats.code,
+            stats.comments,
+            stats.blanks
+        );
+    }
+
+    let mut details = Vec::new();
+
+    if let Some(released) = &output.released {
+        details.push(format!("Released {}", escape(released)));
+    }
+
+    if let Some(commit) = &output.commit {
+        details.push(format!("Commit {}", escape(commit)));
+    }
+
+    if !output.tokei_version.is_empty() {
+        details.push(format!(
+            "Analyzed with tokei {}",
+            escape(&output.tokei_version)
+        ));
+    }
+
+    TEMPLATE
+        .replace("{{title}}", &format!("Linux {}", escape(&output.tag)))
+        .replace("{{details}}", &details.join(" &middot; "))
+        .replace("{{chart}}", &chart(&languages))
+        .replace("{{rows}}", rows.trim_end())
+}
@@ -1,37 +1,306 @@
 //! list of old kernel versions.
 
+use crate::error::Error;
+use crate::version::Version;
 use anyhow::{anyhow, Result};
-use log::{info, warn};
-use serde_derive::Deserialize;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fs;
-use std::io::{Cursor, Read, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub const URL_BASE: &'static str = "https://mirrors.kernel.org/pub/linux/kernel";
 const KERNELS: &'static str = include_str!("kernels.yaml");
 
 /// Get all kernel versions.
 pub fn kernels() -> Result<Kernels> {
-    serde_yaml::from_str(KERNELS).map_err(|e| anyhow!("failed to deserialize kernels: {}", e))
+    let mut kernels: Kernels = serde_yaml::from_str(KERNELS)
+        .map_err(|e| anyhow!("failed to deserialize kernels: {}", e))?;
+    validate_releases(&kernels.releases)?;
+    validate_sources(&mut kernels)?;
+    Ok(kernels)
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Get all kernel versions from the given file, which has the same format as
+/// the embedded `kernels.yaml`.
+pub fn kernels_from_file(path: &Path) -> Result<Kernels> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read kernels: {}: {}", path.display(), e))?;
+    let mut kernels: Kernels = serde_yaml::from_str(&source)
+        .map_err(|e| anyhow!("failed to deserialize kernels: {}: {}", path.display(), e))?;
+    validate_releases(&kernels.releases).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    validate_sources(&mut kernels).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    Ok(kernels)
+}
+
+/// Validate the declared sources and normalize their urls, reporting every
+/// problem found.
+///
+/// This detects duplicate and empty names, bad urls, and releases which
+/// prefer a source that isn't declared.
+fn validate_sources(kernels: &mut Kernels) -> Result<()> {
+    let mut seen = HashSet::new();
+    let mut problems = Vec::new();
+
+    for (index, source) in kernels.sources.iter_mut().enumerate() {
+        if source.name.is_empty() {
+            problems.push(format!("source #{}: empty name", index));
+        } else if !seen.insert(source.name.as_str()) {
+            problems.push(format!(
+                "source #{}: duplicate name: {}",
+                index, source.name
+            ));
+        }
+
+        match parse_mirror(&source.url) {
+            Ok(url) => source.url = url,
+            Err(e) => problems.push(format!("source #{}: {}", index, e)),
+        }
+    }
+
+    for (index, release) in kernels.releases.iter().enumerate() {
+        if let Some(source) = &release.source {
+            if !seen.contains(source.as_str()) {
+                problems.push(format!(
+                    "release #{}: unknown source: {}: {}",
+                    index, release.version, source
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow!("bad kernel sources:\n{}", problems.join("\n")));
+    }
+
+    Ok(())
+}
+
+/// Validate the given releases, reporting every problem found.
+///
+/// This detects duplicate and empty versions, and versions which don't have
+/// a custom path and can't be decomposed into a path on the mirror.
+pub fn validate_releases(releases: &[KernelRelease]) -> Result<()> {
+    let mut seen = HashSet::new();
+    let mut problems = Vec::new();
+
+    for (index, release) in releases.iter().enumerate() {
+        let version = release.version.as_str();
+
+        if version.is_empty() {
+            problems.push(format!("release #{}: empty version", index));
+            continue;
+        }
+
+        if !seen.insert(version) {
+            problems.push(format!(
+                "release #{}: duplicate version: {}",
+                index, version
+            ));
+        }
+
+        if release.path.is_none()
+            && version
+                .split('.')
+                .any(|part| part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()))
+        {
+            problems.push(format!(
+                "release #{}: malformed version: {}: expected `major.minor.patch`",
+                index, version
+            ));
+        }
+
+        if let Some(sha256) = &release.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(format!(
+                    "release #{}: malformed sha256: {}: {}",
+                    index, version, sha256
+                ));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow!("bad kernel releases:\n{}", problems.join("\n")));
+    }
+
+    Ok(())
+}
+
+/// Parse and normalize the base URL of a mirror.
+///
+/// Trailing slashes are stripped so that the base can be joined with the path
+/// of a release.
+pub fn parse_mirror(mirror: &str) -> Result<String> {
+    reqwest::Url::parse(mirror).map_err(|e| anyhow!("bad mirror url: {}: {}", mirror, e))?;
+    Ok(mirror.trim_end_matches('/').to_string())
+}
+
+/// Get the major version of a release or tag, like `6` for both `6.6` and
+/// `v6.6`.
+///
+/// Returns `None` if the version doesn't start with a numeric component.
+pub fn major_version(version: &str) -> Option<&str> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let major = version.split('.').next()?;
+
+    if major.is_empty() || !major.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(major)
+}
+
+/// Options for the HTTP client used for downloads.
+#[derive(Debug, Default)]
+pub struct ClientOptions {
+    /// Proxy to use for all requests.
+    ///
+    /// If not set, proxies are picked up from the `HTTP_PROXY`, `HTTPS_PROXY`
+    /// and `NO_PROXY` environment variables.
+    pub proxy: Option<String>,
+    /// Timeout for connecting to a mirror.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout for a whole request, including downloading the body.
+    pub timeout: Option<Duration>,
+}
+
+impl ClientOptions {
+    /// Build a client with the current options.
+    ///
+    /// The client keeps connections alive, so it should be shared across all
+    /// downloads.
+    pub fn build(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy.as_str())
+                .map_err(|e| anyhow!("bad proxy: {}: {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+
+        builder
+            .build()
+            .map_err(|e| anyhow!("failed to build http client: {}", e))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Kernels {
+    /// Named sources of archives, which are used instead of the default
+    /// mirror if no mirrors are configured.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<Source>,
     pub releases: Vec<KernelRelease>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// A named source of archives, laid out like the kernel.org mirror.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Source {
+    /// The name that releases refer to the source by.
+    pub name: String,
+    /// The base url of the source.
+    pub url: String,
+}
+
+/// Find the name of the source with the given url.
+pub fn source_name<'a>(sources: &'a [Source], url: &str) -> Option<&'a str> {
+    sources
+        .iter()
+        .find(|s| s.url == url)
+        .map(|s| s.name.as_str())
+}
+
+/// The format that a release is archived in.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "tar.bz2")]
+    TarBz2,
+    #[serde(rename = "tar")]
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// The file extension of archives in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarBz2 => "tar.bz2",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct KernelRelease {
+    version: String,
     /// If this version is important.
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "is_false")]
     pub important: bool,
-    version: String,
     /// Custom path to download the kernel, relative to the mirror.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
+    /// The date the kernel was released, in ISO-8601 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// Skip the git tag of this release, like when it doesn't point to a
+    /// commit. The release is still downloaded.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub skip: bool,
+    /// The git tag of the release, if it isn't `v{version}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag_override: Option<String>,
+    /// The expected SHA256 of the archive, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The format the release is archived in on the mirror, if it isn't
+    /// `tar.gz`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ArchiveFormat>,
+    /// The name of the source to try first for this release, see [Source].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 impl KernelRelease {
+    /// Get the version of the release, like `2.6.39`.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Get the format the release is archived in.
+    pub fn format(&self) -> ArchiveFormat {
+        self.format.unwrap_or(ArchiveFormat::TarGz)
+    }
+
+    /// Get the git tag of the release.
+    pub fn tag(&self) -> String {
+        match &self.tag_override {
+            Some(tag) => tag.clone(),
+            None => format!("v{}", self.version),
+        }
+    }
+
     fn path(&self) -> String {
         if let Some(path) = self.path.as_ref() {
             return path.to_string();
@@ -39,30 +308,47 @@ impl KernelRelease {
 
         let version = self.version.as_str();
 
-        let mut parts = version.split(".");
-        let major = parts.next().unwrap_or_else(|| "expected major version");
-        let minor = match parts.next() {
+        let major = major_version(version).unwrap_or("expected major version");
+        let minor = match version.split('.').nth(1) {
             Some(minor) => minor,
             None => "x",
         };
 
-        let name = match version {
-            "1.1.0" => format!("v{}", version),
-            _ => format!("linux-{version}", version = version),
-        };
-
         format!(
-            "v{major}.{minor}/{name}.tar.gz",
+            "v{major}.{minor}/linux-{version}.{extension}",
             major = major,
             minor = minor,
-            name = name,
+            version = version,
+            extension = self.format().extension(),
         )
     }
 
-    /// Get the downloadable URL for the given kernel version.
-    pub fn tar_gz_url(&self) -> Result<String> {
+    /// Get the path that the archive of this release is cached at.
+    pub fn cache_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("linux-{}.{}", self, self.format().extension()))
+    }
+
+    /// Order the given mirrors by preference for this release, with the url
+    /// of its preferred source first if it's one of them.
+    pub fn mirrors<'m>(&self, mirrors: &'m [String], sources: &[Source]) -> Vec<&'m str> {
+        let preferred = self
+            .source
+            .as_ref()
+            .and_then(|name| sources.iter().find(|s| s.name == *name))
+            .map(|s| s.url.as_str());
+
+        let mut ordered = mirrors.iter().map(String::as_str).collect::<Vec<_>>();
+
+        // NB: the sort is stable, so the other mirrors keep their order.
+        ordered.sort_by_key(|m| Some(*m) != preferred);
+        ordered
+    }
+
+    /// Get the downloadable URL for the given kernel version from the given
+    /// mirror.
+    pub fn archive_url(&self, mirror: &str) -> Result<String> {
         let path = self.path();
-        Ok(format!("{base}/{path}", base = URL_BASE, path = path))
+        Ok(format!("{base}/{path}", base = mirror, path = path))
     }
 }
 
@@ -72,31 +358,763 @@ impl fmt::Display for KernelRelease {
     }
 }
 
+/// Extract the targets of all links in an HTML directory listing.
+fn listing_links(html: &str) -> Vec<&str> {
+    let mut links = Vec::new();
+    let mut rest = html;
+
+    while let Some(index) = rest.find("href=\"") {
+        rest = &rest[index + 6..];
+
+        let end = match rest.find('"') {
+            Some(end) => end,
+            None => break,
+        };
+
+        // NB: links might be absolute, so only the last component is used.
+        let link = rest[..end].trim_end_matches('/');
+        links.push(link.rsplit('/').next().unwrap_or(link));
+        rest = &rest[end..];
+    }
+
+    links
+}
+
+/// Test if the given directory on the mirror holds releases, like `v2.6` or
+/// `v6.x`.
+fn is_release_dir(name: &str) -> bool {
+    let mut parts = match name.strip_prefix('v') {
+        Some(rest) => rest.split('.'),
+        None => return false,
+    };
+
+    let major = parts.next().unwrap_or_default();
+    let minor = parts.next().unwrap_or_default();
+
+    !major.is_empty()
+        && major.chars().all(|c| c.is_ascii_digit())
+        && (minor == "x" || (!minor.is_empty() && minor.chars().all(|c| c.is_ascii_digit())))
+        && parts.next().is_none()
+}
+
+/// Parse the name of a release archive on the mirror, like
+/// `linux-2.6.39.tar.bz2`.
+fn parse_archive_name(name: &str) -> Option<(&str, ArchiveFormat)> {
+    let name = name.strip_prefix("linux-")?;
+
+    let (version, format) = if let Some(version) = name.strip_suffix(".tar.gz") {
+        (version, ArchiveFormat::TarGz)
+    } else if let Some(version) = name.strip_suffix(".tar.bz2") {
+        (version, ArchiveFormat::TarBz2)
+    } else {
+        return None;
+    };
+
+    // NB: this excludes release candidates and patch sets.
+    if version
+        .split('.')
+        .any(|part| part.is_empty() || !part.chars().all(|c| c.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    Some((version, format))
+}
+
+/// Fetch the directory listing at the given URL.
+async fn fetch_listing(client: &reqwest::Client, url: &str) -> Result<String> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| anyhow!("failed to fetch listing: {}: {}", url, e))?;
+
+    let res = res
+        .error_for_status()
+        .map_err(|e| anyhow!("failed to fetch listing: {}: {}", url, e))?;
+
+    res.text()
+        .await
+        .map_err(|e| anyhow!("failed to read listing: {}: {}", url, e))
+}
+
+/// Scrape the directory listings of the given mirror for releases.
+///
+/// Every `linux-{version}` archive in directories like `v2.6/` is returned,
+/// preferring `tar.gz` over `tar.bz2` if a release is archived in both.
+pub async fn scrape_mirror(client: &reqwest::Client, mirror: &str) -> Result<Vec<KernelRelease>> {
+    let root = fetch_listing(client, &format!("{}/", mirror)).await?;
+
+    let mut dirs = listing_links(&root)
+        .into_iter()
+        .filter(|name| is_release_dir(name))
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+
+    // NB: ordered, so that the result is stable between runs.
+    let mut releases = BTreeMap::<String, KernelRelease>::new();
+
+    for dir in dirs {
+        info!("scraping: {}/{}/", mirror, dir);
+        let listing = fetch_listing(client, &format!("{}/{}/", mirror, dir)).await?;
+
+        for name in listing_links(&listing) {
+            let (version, format) = match parse_archive_name(name) {
+                Some(archive) => archive,
+                None => continue,
+            };
+
+            if let Some(existing) = releases.get(version) {
+                if existing.format() == ArchiveFormat::TarGz {
+                    continue;
+                }
+            }
+
+            let mut release = KernelRelease {
+                version: version.to_string(),
+                important: false,
+                path: None,
+                date: None,
+                skip: false,
+                tag_override: None,
+                sha256: None,
+                format: Some(format).filter(|f| *f != ArchiveFormat::TarGz),
+                source: None,
+            };
+
+            // NB: directories like `v6.x` don't match the default path.
+            let path = format!("{}/{}", dir, name);
+
+            if release.path() != path {
+                release.path = Some(path);
+            }
+
+            releases.insert(version.to_string(), release);
+        }
+    }
+
+    Ok(releases.into_iter().map(|entry| entry.1).collect())
+}
+
+/// Merge releases scraped from a mirror into the existing releases.
+///
+/// Existing releases are kept as they are, with all of their annotations, and
+/// scraped releases are only added if their version is new. The result is
+/// sorted by version, and the number of added releases is returned.
+pub fn merge_releases(releases: &mut Vec<KernelRelease>, scraped: Vec<KernelRelease>) -> usize {
+    let known = releases
+        .iter()
+        .map(|r| r.version.clone())
+        .collect::<HashSet<_>>();
+
+    let before = releases.len();
+    releases.extend(scraped.into_iter().filter(|r| !known.contains(&r.version)));
+    let added = releases.len() - before;
+
+    // NB: the sort is stable, so versions which compare equal like `0.01` and
+    // `0.1` keep their existing order, and merging is idempotent.
+    releases.sort_by_key(|r| Version::parse(&r.version));
+    added
+}
+
 #[derive(Debug, Clone)]
 pub struct CachedKernel<'a> {
     pub version: &'a KernelRelease,
     pub path: PathBuf,
+    /// The mirror which served or revalidated the archive, if it was fetched
+    /// from a mirror in this run.
+    pub mirror: Option<String>,
+    /// The name of the source that `mirror` was declared as, if any.
+    pub source: Option<String>,
+    /// Time spent downloading the archive, which is zero if it was already
+    /// cached.
+    pub download_time: Duration,
+    /// The SHA256 checksum of the archive, if it's known without reading it
+    /// again, like when it was just downloaded.
+    pub sha256: Option<String>,
+}
+
+/// How often the progress of a download is logged, if stderr isn't a
+/// terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Responses smaller than this can't be a kernel archive, and are most likely
+/// an error page.
+const MIN_ARCHIVE_SIZE: u64 = 1024;
+
+/// A progress bar for downloads, which is only drawn if stderr is a terminal,
+/// since that's where it's drawn.
+///
+/// Otherwise, the progress of each download is logged periodically.
+///
+/// NB: a single bar is used over a `MultiProgress`, which only draws while
+/// something joins it. It can't be joined from another thread while bars are
+/// added for new downloads.
+struct Progress {
+    /// The bar, and the number of bytes received over all downloads.
+    bar: Option<(ProgressBar, Arc<AtomicU64>)>,
+}
+
+impl Progress {
+    /// Set up progress for the given total number of downloads.
+    fn new(total: usize) -> Progress {
+        if !atty::is(atty::Stream::Stderr) {
+            return Progress { bar: None };
+        }
+
+        let bar = ProgressBar::new(total as u64);
+        bar.set_style(
+            ProgressStyle::default_bar().template("{pos}/{len} complete, {msg} {wide_bar}"),
+        );
+        bar.set_message(format!("{} received", format_bytes(0)));
+
+        Progress {
+            bar: Some((bar, Arc::new(AtomicU64::new(0)))),
+        }
+    }
+
+    /// Track the bytes received for a single download.
+    fn download(&self, name: String, len: Option<u64>) -> DownloadBar {
+        match &self.bar {
+            Some((bar, received)) => DownloadBar::Bar {
+                bar: bar.clone(),
+                received: received.clone(),
+            },
+            None => {
+                let now = Instant::now();
+
+                DownloadBar::Log {
+                    name,
+                    len,
+                    received: 0,
+                    started: now,
+                    logged: now,
+                }
+            }
+        }
+    }
+
+    /// Mark a single download as complete.
+    fn complete(&self) {
+        if let Some((bar, _)) = &self.bar {
+            bar.inc(1);
+        }
+    }
+}
+
+/// Clear the progress once downloading is done, or has failed.
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some((bar, _)) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+/// Progress of a single download.
+enum DownloadBar {
+    /// Bytes are added to those received over all downloads.
+    Bar {
+        bar: ProgressBar,
+        received: Arc<AtomicU64>,
+    },
+    /// Progress which is logged every [LOG_INTERVAL].
+    Log {
+        name: String,
+        len: Option<u64>,
+        received: u64,
+        started: Instant,
+        logged: Instant,
+    },
+}
+
+impl DownloadBar {
+    /// Record that the given number of bytes were received.
+    fn inc(&mut self, bytes: usize) {
+        match self {
+            DownloadBar::Bar { bar, received } => {
+                let received = received.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+                bar.set_message(format!("{} received", format_bytes(received)));
+            }
+            DownloadBar::Log {
+                name,
+                len,
+                received,
+                started,
+                logged,
+            } => {
+                *received += bytes as u64;
+
+                let now = Instant::now();
+
+                if now.duration_since(*logged) < LOG_INTERVAL {
+                    return;
+                }
+
+                *logged = now;
+
+                let elapsed = now.duration_since(*started).as_secs_f64();
+                let rate = format_bytes((*received as f64 / elapsed) as u64);
+
+                match *len {
+                    Some(len) if len > 0 => info!(
+                        "{}: {}% ({}/{}) at {}/s",
+                        name,
+                        *received * 100 / len,
+                        format_bytes(*received),
+                        format_bytes(len),
+                        rate
+                    ),
+                    _ => info!("{}: {} at {}/s", name, format_bytes(*received), rate),
+                }
+            }
+        }
+    }
+}
+
+/// Format a number of bytes for humans, like `120MB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 || value >= 100.0 {
+        format!("{:.0}{}", value, UNITS[unit])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// The result of downloading kernels.
+#[derive(Debug)]
+pub struct Downloaded<'a> {
+    /// Kernels which were downloaded or already cached.
+    pub cached: Vec<CachedKernel<'a>>,
+    /// Kernels which failed to download, if failures were allowed.
+    pub failed: Vec<(&'a KernelRelease, Error)>,
+}
+
+/// Token bucket limiting the aggregate throughput of all downloads.
+///
+/// Downloads take tokens for every chunk received, and wait for the bucket to
+/// refill once it's been drained.
+struct RateLimiter {
+    /// Bytes per second.
+    rate: f64,
+    /// Available tokens and when they were last refilled.
+    state: std::sync::Mutex<(f64, std::time::Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            state: std::sync::Mutex::new((rate as f64, std::time::Instant::now())),
+        }
+    }
+
+    /// Take tokens for the given number of bytes, waiting if the bucket has
+    /// been drained.
+    async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+
+            let now = std::time::Instant::now();
+            // NB: allow at most a second worth of burst.
+            *tokens = f64::min(
+                self.rate,
+                *tokens + now.duration_since(*last).as_secs_f64() * self.rate,
+            );
+            *last = now;
+            *tokens -= bytes as f64;
+
+            if *tokens < 0.0 {
+                Some(Duration::from_secs_f64(-*tokens / self.rate))
+            } else {
+                None
+            }
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::delay_for(wait).await;
+        }
+    }
+}
+
+/// Reader over chunks of data received from a channel.
+struct ChannelReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Vec<u8>>) -> ChannelReader {
+        ChannelReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // NB: sender is gone, so this is the end of the data.
+                Err(mpsc::RecvError) => return Ok(0),
+            }
+        }
+
+        let n = usize::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Reader which writes everything that is read through it to a writer.
+struct TeeReader<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Read for TeeReader<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Get the path of the sidecar file storing the ETag that the archive at
+/// `path` was served with.
+fn etag_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".etag");
+    PathBuf::from(path)
+}
+
+/// Get the path that the archive at `path` is downloaded to, before it's
+/// complete and renamed into place.
+fn part_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".part");
+    PathBuf::from(path)
+}
+
+/// Build the headers for a conditional request, which is only served if the
+/// cached archive at `path` has changed upstream.
+fn conditional_headers(path: &Path) -> Result<reqwest::header::HeaderMap> {
+    use reqwest::header::{HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+
+    let mut headers = HeaderMap::new();
+    let etag = etag_path(path);
+
+    if etag.is_file() {
+        let etag = fs::read_to_string(&etag)
+            .map_err(|e| anyhow!("failed to read: {}: {}", etag.display(), e))?;
+
+        if let Ok(value) = HeaderValue::from_str(etag.trim()) {
+            headers.insert(IF_NONE_MATCH, value);
+        }
+    }
+
+    let modified = fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| anyhow!("failed to read metadata: {}: {}", path.display(), e))?;
+
+    if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+        headers.insert(IF_MODIFIED_SINCE, value);
+    }
+
+    Ok(headers)
+}
+
+/// Build the headers of a request for an archive, given the `headers` of a
+/// conditional request.
+///
+/// If a partial download of `offset` bytes is being resumed, only the rest of
+/// the archive is requested. If the partial download was started with an
+/// `etag`, it's only resumed if the archive hasn't changed since.
+pub fn resume_request(
+    headers: &reqwest::header::HeaderMap,
+    offset: Option<u64>,
+    etag: Option<&str>,
+) -> Result<reqwest::header::HeaderMap> {
+    use reqwest::header::{HeaderValue, IF_RANGE, RANGE};
+
+    let mut request = headers.clone();
+
+    if let Some(offset) = offset {
+        request.insert(RANGE, HeaderValue::from_str(&format!("bytes={}-", offset))?);
+
+        if let Some(Ok(value)) = etag.map(|etag| HeaderValue::from_str(etag.trim())) {
+            request.insert(IF_RANGE, value);
+        }
+    }
+
+    Ok(request)
+}
+
+/// How a download continues after the response to a request built with
+/// [resume_request].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// The rest of the archive is served, after the given offset.
+    From(u64),
+    /// The whole archive is served.
+    Start,
+    /// The partial download can't be resumed, so the whole archive has to be
+    /// requested again.
+    Restart,
+}
+
+/// Determine how a download of `offset` bytes continues from the `status`
+/// and `headers` of the response to a request built with [resume_request].
+///
+/// Errors if the response resumes the download from somewhere else.
+pub fn resume_response(
+    offset: Option<u64>,
+    status: reqwest::StatusCode,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<Resume> {
+    use reqwest::header::CONTENT_RANGE;
+    use reqwest::StatusCode;
+
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Ok(Resume::Start),
+    };
+
+    match status {
+        StatusCode::RANGE_NOT_SATISFIABLE => Ok(Resume::Restart),
+        StatusCode::PARTIAL_CONTENT => {
+            let range = headers
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+
+            if !range.starts_with(&format!("bytes {}-", offset)) {
+                return Err(anyhow!("unexpected content range: {}", range));
+            }
+
+            Ok(Resume::From(offset))
+        }
+        // NB: mirrors which don't support ranges serve the whole archive.
+        _ => Ok(Resume::Start),
+    }
+}
+
+/// Check the `headers` of a response which should serve an archive.
+///
+/// Some mirrors serve an HTML error page with a successful status instead of
+/// a 404. The remainder of a download resumed from an `offset` can be
+/// arbitrarily small, so its size isn't checked.
+pub fn check_served(headers: &reqwest::header::HeaderMap, offset: Option<u64>) -> Result<()> {
+    use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    if content_type.to_ascii_lowercase().starts_with("text/html") {
+        return Err(anyhow!("mirror served an html page instead of an archive"));
+    }
+
+    let len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+
+    if let Some(len) = len.filter(|&len| offset.is_none() && len < MIN_ARCHIVE_SIZE) {
+        return Err(anyhow!(
+            "mirror served {} bytes, which is too small to be an archive",
+            len
+        ));
+    }
+
+    Ok(())
+}
+
+/// Compute the SHA256 checksum of the file at the given path, in hex.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    use sha2::{Digest as _, Sha256};
+    use std::io::Read as _;
+
+    let mut f =
+        fs::File::open(path).map_err(|e| anyhow!("failed to open: {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+
+    loop {
+        let n = f
+            .read(&mut buf)
+            .map_err(|e| anyhow!("failed to read: {}: {}", path.display(), e))?;
+
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Get the path of the object with the given SHA256 in the content-addressed
+/// store of the cache.
+fn object_path(root: &Path, sha256: &str) -> PathBuf {
+    root.join("objects").join(sha256)
+}
+
+/// Move the downloaded archive at `path` into the content-addressed store,
+/// and replace it with a hard link to the stored object.
+///
+/// If the store already has an identical archive, the downloaded one is
+/// discarded. Returns the path of the object.
+fn store_object(root: &Path, path: &Path, sha256: &str) -> Result<PathBuf> {
+    let object = object_path(root, sha256);
+
+    if let Some(parent) = object.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| anyhow!("failed to create directory: {}: {}", parent.display(), e))?;
+    }
+
+    if object.is_file() {
+        info!("deduplicated: {} -> {}", path.display(), object.display());
+        fs::remove_file(path)
+            .map_err(|e| anyhow!("failed to remove: {}: {}", path.display(), e))?;
+    } else {
+        fs::rename(path, &object).map_err(|e| {
+            anyhow!(
+                "failed to rename: {} -> {}: {}",
+                path.display(),
+                object.display(),
+                e
+            )
+        })?;
+    }
+
+    link_object(&object, path)?;
+    Ok(object)
+}
+
+/// Hard link the given object to `path`.
+fn link_object(object: &Path, path: &Path) -> Result<()> {
+    fs::hard_link(object, path).map_err(|e| {
+        anyhow!(
+            "failed to link: {} -> {}: {}",
+            path.display(),
+            object.display(),
+            e
+        )
+    })
 }
 
 /// Download the archives of the listed versions in parallel.
+///
+/// Each archive is downloaded from the first mirror in `mirrors` which can
+/// serve it, trying the source the release prefers among `sources` first.
+/// A failed download is attempted again up to `retries` times. If
+/// `keep_going` is set, failed downloads are collected instead of aborting
+/// all downloads.
+///
+/// If `dedup` is set, downloaded archives are stored by their SHA256 under
+/// `objects/` in the cache, with version-named hard links pointing at them.
+///
+/// If `max_bandwidth` is set, it limits the bytes per second received across
+/// all downloads.
+///
+/// If `revalidate` is set, cached archives are checked against the mirrors
+/// with a conditional request, and are downloaded again if they've changed.
+///
+/// If `min_free_space` is set, no new download is started while the cache
+/// file system has less than this many bytes available. It's an error if
+/// there still isn't enough space once all running downloads are done.
+#[allow(clippy::too_many_arguments)]
 pub async fn download_old_kernels<'a>(
+    client: &reqwest::Client,
     root: &Path,
     versions: &'a [KernelRelease],
+    mirrors: &[String],
+    sources: &[Source],
     verify: bool,
+    revalidate: bool,
     parallelism: usize,
-) -> Result<Vec<CachedKernel<'a>>> {
+    keep_going: bool,
+    retries: usize,
+    dedup: bool,
+    max_bandwidth: Option<u64>,
+    min_free_space: Option<u64>,
+) -> Result<Downloaded<'a>, Error> {
     let total = versions.len();
-    let mut results = Vec::new();
+    let mut cached = Vec::new();
+    let mut failed = Vec::new();
 
-    let mut it = versions.iter().enumerate();
+    let mut it = versions.iter().enumerate().peekable();
+    let progress = Progress::new(total);
+    let limiter = max_bandwidth.map(RateLimiter::new);
     let mut tasks = unicycle::FuturesUnordered::new();
     let mut count = 0;
 
     loop {
-        if count < parallelism {
+        let ready = match it.peek() {
+            Some((_, version)) if count < parallelism => {
+                // NB: archives which are already cached don't need any space.
+                version.cache_path(root).is_file()
+                    || has_free_space(root, min_free_space, tasks.is_empty()).map_err(Error::Io)?
+            }
+            _ => false,
+        };
+
+        if ready {
             if let Some((index, version)) = it.next() {
                 count += 1;
-                tasks.push(download_archive(index, total, root, version, verify));
+                let progress = &progress;
+                let limiter = limiter.as_ref();
+
+                tasks.push(async move {
+                    let mut attempt = 0;
+
+                    let result = loop {
+                        let result = download_archive(
+                            client, index, total, root, version, mirrors, sources, verify,
+                            revalidate, dedup, progress, limiter,
+                        )
+                        .await;
+
+                        match result {
+                            Err(e) if attempt < retries => {
+                                attempt += 1;
+                                warn!(
+                                    "{}/{}: failed to download: {}: {}: retrying ({}/{})",
+                                    index, total, version, e, attempt, retries
+                                );
+                            }
+                            result => break result,
+                        }
+                    };
+                    (version, result)
+                });
                 continue;
             }
         }
@@ -105,21 +1123,61 @@ pub async fn download_old_kernels<'a>(
             break;
         }
 
-        results.push(tasks.next().await.unwrap()?);
+        match tasks.next().await.unwrap() {
+            (_, Ok(kernel)) => cached.push(kernel),
+            (version, Err(e)) if keep_going => {
+                warn!("failed to download: {}: {}", version, e);
+                failed.push((version, e));
+            }
+            (_, Err(e)) => return Err(e),
+        }
+
+        progress.complete();
         count -= 1;
     }
 
-    return Ok(results);
+    return Ok(Downloaded { cached, failed });
 
     /// Download the specified archive.
+    #[allow(clippy::too_many_arguments)]
     async fn download_archive<'a>(
+        client: &reqwest::Client,
         index: usize,
         total: usize,
         root: &Path,
         version: &'a KernelRelease,
+        mirrors: &[String],
+        sources: &[Source],
         verify: bool,
-    ) -> Result<CachedKernel<'a>> {
-        let path = root.join(format!("linux-{}.tar.gz", version));
+        revalidate: bool,
+        dedup: bool,
+        progress: &Progress,
+        limiter: Option<&RateLimiter>,
+    ) -> Result<CachedKernel<'a>, Error> {
+        let path = version.cache_path(root);
+
+        // NB: with a known checksum, an identical archive might already be
+        // stored for another version.
+        if dedup && !path.is_file() {
+            if let Some(sha256) = &version.sha256 {
+                let object = object_path(root, sha256);
+
+                if object.is_file() {
+                    link_object(&object, &path).map_err(Error::Io)?;
+                    info!("{}/{}: OK: {}", index, total, object.display());
+                    return Ok(CachedKernel {
+                        version,
+                        path: object,
+                        mirror: None,
+                        source: None,
+                        download_time: Duration::default(),
+                        sha256: Some(sha256.clone()),
+                    });
+                }
+            }
+        }
+
+        let mut headers = reqwest::header::HeaderMap::new();
 
         // use existing path if it already exists.
         if path.is_file() {
@@ -128,8 +1186,9 @@ pub async fn download_old_kernels<'a>(
                     Ok(()) => true,
                     Err(e) => {
                         warn!("ignoring bad archive: {}: {}", path.display(), e);
-                        fs::remove_file(&path)
-                            .map_err(|e| anyhow!("failed to remove: {}: {}", path.display(), e))?;
+                        fs::remove_file(&path).map_err(|e| {
+                            Error::Io(anyhow!("failed to remove: {}: {}", path.display(), e))
+                        })?;
                         false
                     }
                 }
@@ -137,87 +1196,803 @@ pub async fn download_old_kernels<'a>(
                 true
             };
 
-            if ok {
+            if ok && !revalidate {
                 info!("{}/{}: OK: {}", index, total, path.display());
-                return Ok(CachedKernel { version, path });
+                return Ok(CachedKernel {
+                    version,
+                    path,
+                    mirror: None,
+                    source: None,
+                    download_time: Duration::default(),
+                    sha256: None,
+                });
+            }
+
+            if ok {
+                headers = conditional_headers(&path).map_err(Error::Io)?;
             }
         }
 
-        let url = version.tar_gz_url()?;
+        let mut last_error = None;
+        let started = Instant::now();
 
-        info!(
-            "{}/{}: downloading {} -> {}",
-            index,
-            total,
-            url,
-            path.display()
-        );
+        for mirror in version.mirrors(mirrors, sources) {
+            let source = source_name(sources, mirror).map(str::to_string);
 
-        let res = reqwest::get(&url)
+            match download_from(
+                client, index, total, &path, version, mirror, &headers, progress, limiter,
+            )
             .await
-            .map_err(|e| anyhow!("failed to get url: {}: {}", url, e))?;
+            {
+                Ok(Fetched::NotModified) => {
+                    info!("{}/{}: OK: {} (not modified)", index, total, path.display());
+                    return Ok(CachedKernel {
+                        version,
+                        path,
+                        mirror: Some(mirror.to_string()),
+                        source,
+                        download_time: started.elapsed(),
+                        sha256: None,
+                    });
+                }
+                Ok(Fetched::Downloaded(sha256)) => {
+                    info!("{}/{}: {} served by: {}", index, total, version, mirror);
+
+                    let path = if dedup {
+                        store_object(root, &path, &sha256).map_err(Error::Io)?
+                    } else {
+                        path
+                    };
+
+                    return Ok(CachedKernel {
+                        version,
+                        path,
+                        mirror: Some(mirror.to_string()),
+                        source,
+                        download_time: started.elapsed(),
+                        sha256: Some(sha256),
+                    });
+                }
+                Err(e) => {
+                    warn!("{}/{}: {}: {}", index, total, mirror, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(match last_error {
+            Some(e) => e.context(format!("no mirror could serve: {}", version)),
+            None => Error::Download(anyhow!("no mirrors to download from: {}", version)),
+        })
+    }
+
+    /// The result of fetching an archive from a mirror.
+    enum Fetched {
+        /// The cached archive is still valid, as determined by a conditional
+        /// request.
+        NotModified,
+        /// The archive was downloaded, with the given SHA256.
+        Downloaded(String),
+    }
+
+    /// Download the specified archive from a single mirror.
+    ///
+    /// The request is sent with the given `headers`, which can make it
+    /// conditional on the cached archive having changed.
+    #[allow(clippy::too_many_arguments)]
+    async fn download_from(
+        client: &reqwest::Client,
+        index: usize,
+        total: usize,
+        path: &Path,
+        version: &KernelRelease,
+        mirror: &str,
+        headers: &reqwest::header::HeaderMap,
+        progress: &Progress,
+        limiter: Option<&RateLimiter>,
+    ) -> Result<Fetched, Error> {
+        use sha2::{Digest as _, Sha256};
+
+        let url = version.archive_url(mirror).map_err(Error::Download)?;
+        debug!("{}/{}: {} resolved to: {}", index, total, version, url);
+
+        let part = part_path(path);
+
+        // NB: a partial download is only resumed if there's no cached
+        // archive, since conditional requests are about the cached archive.
+        let mut resume = match fs::metadata(&part) {
+            Ok(m) if headers.is_empty() && m.len() > 0 => Some(m.len()),
+            _ => None,
+        };
+
+        match resume {
+            Some(offset) => {
+                info!(
+                    "{}/{}: resuming {} from {} bytes -> {}",
+                    index,
+                    total,
+                    url,
+                    offset,
+                    part.display()
+                );
+            }
+            None => {
+                info!(
+                    "{}/{}: downloading {} -> {}",
+                    index,
+                    total,
+                    url,
+                    path.display()
+                );
+            }
+        }
+
+        // NB: only resume if the archive hasn't changed since the partial
+        // download was started.
+        let etag = match resume {
+            Some(_) => fs::read_to_string(etag_path(&part)).ok(),
+            None => None,
+        };
+
+        let request = resume_request(headers, resume, etag.as_deref()).map_err(Error::Download)?;
+
+        let mut res = client
+            .get(&url)
+            .headers(request)
+            .send()
+            .await
+            .map_err(|e| Error::Download(anyhow!("failed to get url: {}: {}", url, e)))?;
+
+        if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::NotModified);
+        }
+
+        let mut resumed = resume_response(resume, res.status(), res.headers()).map_err(|e| {
+            remove_partial(&part);
+            Error::Download(anyhow!("failed to resume: {}: {}", url, e))
+        })?;
+
+        if resumed == Resume::Restart {
+            warn!(
+                "{}/{}: can't resume: {}: downloading from the start",
+                index,
+                total,
+                part.display()
+            );
+            remove_partial(&part);
+            resume = None;
+            resumed = Resume::Start;
+
+            res = client
+                .get(&url)
+                .headers(headers.clone())
+                .send()
+                .await
+                .map_err(|e| Error::Download(anyhow!("failed to get url: {}: {}", url, e)))?;
+        }
 
         if !res.status().is_success() {
-            return Err(anyhow!("failed to download: {}: {}", url, res.status()).into());
+            return Err(Error::Download(anyhow!(
+                "failed to download: {}: {}",
+                url,
+                res.status()
+            )));
         }
 
-        let buf = res.bytes().await?;
+        let offset = match resumed {
+            Resume::From(offset) => Some(offset),
+            _ if resume.is_some() => {
+                info!(
+                    "{}/{}: {} doesn't support resuming, downloading from the start",
+                    index, total, mirror
+                );
+                None
+            }
+            _ => None,
+        };
+
+        check_served(res.headers(), offset)
+            .map_err(|e| Error::Download(anyhow!("failed to download: {}: {}", url, e)))?;
 
-        if let Err(e) = test_reader_archive(Cursor::new(&buf)) {
-            return Err(anyhow!(
-                "test on downloaded archive failed: {}: {}",
+        let etag = res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+
+        let mut hasher = Sha256::new();
+
+        let (out, prefix): (fs::File, Box<dyn Read + Send>) = match offset {
+            Some(offset) => {
+                let mut existing = fs::File::open(&part).map_err(|e| {
+                    Error::Io(anyhow!("failed to open file: {}: {}", part.display(), e))
+                })?;
+                let mut buf = vec![0; 1 << 16];
+
+                loop {
+                    let n = existing.read(&mut buf).map_err(|e| {
+                        Error::Io(anyhow!("failed to read: {}: {}", part.display(), e))
+                    })?;
+
+                    if n == 0 {
+                        break;
+                    }
+
+                    hasher.update(&buf[..n]);
+                }
+
+                let existing = fs::File::open(&part).map_err(|e| {
+                    Error::Io(anyhow!("failed to open file: {}: {}", part.display(), e))
+                })?;
+
+                let out = fs::OpenOptions::new()
+                    .append(true)
+                    .open(&part)
+                    .map_err(|e| {
+                        Error::Io(anyhow!("failed to open file: {}: {}", part.display(), e))
+                    })?;
+
+                (out, Box::new(existing.take(offset)))
+            }
+            None => {
+                let out = fs::File::create(&part).map_err(|e| {
+                    Error::Io(anyhow!("failed to open file: {}: {}", part.display(), e))
+                })?;
+
+                let part_etag = etag_path(&part);
+
+                match &etag {
+                    Some(etag) => fs::write(&part_etag, etag).map_err(|e| {
+                        Error::Io(anyhow!("failed to write: {}: {}", part_etag.display(), e))
+                    })?,
+                    None if part_etag.is_file() => fs::remove_file(&part_etag).map_err(|e| {
+                        Error::Io(anyhow!("failed to remove: {}: {}", part_etag.display(), e))
+                    })?,
+                    None => (),
+                }
+
+                (out, Box::new(io::empty()))
+            }
+        };
+
+        // NB: the archive is written and validated in the same pass as it's
+        // being downloaded. A resumed archive is validated from the start,
+        // but only the newly downloaded data is written.
+        let (tx, rx) = mpsc::channel();
+
+        let validator = tokio::task::spawn_blocking(move || {
+            let mut reader = prefix.chain(TeeReader {
+                reader: ChannelReader::new(rx),
+                writer: out,
+            });
+
+            test_reader_archive(&mut reader)?;
+            // consume any trailing data so that the archive is complete.
+            io::copy(&mut reader, &mut io::sink())?;
+            let (_, tee) = reader.into_inner();
+            Ok::<_, anyhow::Error>(tee.writer)
+        });
+
+        let len = res.content_length().map(|len| len + offset.unwrap_or(0));
+        let mut bar = progress.download(version.to_string(), len);
+        bar.inc(offset.unwrap_or(0) as usize);
+
+        let downloaded = async {
+            let mut first = offset.is_none();
+
+            while let Some(chunk) = res
+                .chunk()
+                .await
+                .map_err(|e| Error::Download(anyhow!("failed to download: {}: {}", url, e)))?
+            {
+                // NB: also catch error pages served without a content type.
+                if std::mem::take(&mut first) && looks_like_html(&chunk) {
+                    return Err(Error::Download(anyhow!(
+                        "failed to download: {}: mirror served an html page instead of an archive",
+                        url
+                    )));
+                }
+
+                if let Some(limiter) = limiter {
+                    limiter.acquire(chunk.len()).await;
+                }
+
+                bar.inc(chunk.len());
+                hasher.update(&chunk);
+
+                // validator has stopped reading, so it has an error for us.
+                if tx.send(chunk.to_vec()).is_err() {
+                    break;
+                }
+            }
+
+            Ok::<_, Error>(())
+        }
+        .await;
+
+        drop(bar);
+        drop(tx);
+
+        let validated = validator.await.map_err(|e| Error::Download(e.into()))?;
+
+        let out = match (downloaded, validated) {
+            (Ok(()), Ok(out)) => out,
+            (Err(e), _) => {
+                // NB: keep what was downloaded, so that the next attempt can
+                // resume from it.
+                match fs::metadata(&part) {
+                    Ok(m) if m.len() > 0 => {
+                        info!(
+                            "{}/{}: keeping partial download: {}",
+                            index,
+                            total,
+                            part.display()
+                        )
+                    }
+                    _ => remove_partial(&part),
+                }
+
+                return Err(e);
+            }
+            (Ok(()), Err(e)) => {
+                remove_partial(&part);
+                return Err(Error::Decode(anyhow!(
+                    "test on downloaded archive failed: {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        };
+
+        out.sync_all()
+            .map_err(|e| Error::Io(anyhow!("failed to sync: {}: {}", part.display(), e)))?;
+
+        let sha256 = format!("{:x}", hasher.finalize());
+
+        if let Some(expected) = &version.sha256 {
+            if !expected.eq_ignore_ascii_case(&sha256) {
+                remove_partial(&part);
+                return Err(Error::Decode(anyhow!(
+                    "checksum mismatch: {}: expected {} but got {}",
+                    url,
+                    expected,
+                    sha256
+                )));
+            }
+        }
+
+        // NB: renaming replaces an archive which might be a hard link to an
+        // object, instead of overwriting the object in place.
+        fs::rename(&part, path).map_err(|e| {
+            Error::Io(anyhow!(
+                "failed to rename: {} -> {}: {}",
+                part.display(),
                 path.display(),
                 e
-            ));
+            ))
+        })?;
+
+        let part_etag = etag_path(&part);
+
+        if part_etag.is_file() {
+            fs::remove_file(&part_etag).map_err(|e| {
+                Error::Io(anyhow!("failed to remove: {}: {}", part_etag.display(), e))
+            })?;
         }
 
-        let mut out = fs::File::create(&path)
-            .map_err(|e| anyhow!("failed to open file: {}: {}", path.display(), e))?;
+        let etag_path = etag_path(path);
 
-        out.write_all(&buf)
-            .map_err(|e| anyhow!("failed to write file: {}: {}", path.display(), e))?;
-        out.sync_all()
-            .map_err(|e| anyhow!("failed to sync: {}: {}", path.display(), e))?;
+        match etag {
+            Some(etag) => fs::write(&etag_path, etag).map_err(|e| {
+                Error::Io(anyhow!("failed to write: {}: {}", etag_path.display(), e))
+            })?,
+            None if etag_path.is_file() => fs::remove_file(&etag_path).map_err(|e| {
+                Error::Io(anyhow!("failed to remove: {}: {}", etag_path.display(), e))
+            })?,
+            None => (),
+        }
 
-        Ok(CachedKernel { version, path })
+        Ok(Fetched::Downloaded(sha256))
     }
 
-    /// Test that the given path is a proper archive.
-    ///
-    /// Returns a reason string describing what's wrong with the archive if it's not OK.
-    /// Otherwise, returns `None`.
-    fn test_archive(path: &Path) -> Result<()> {
-        let f = match fs::File::open(path) {
-            Err(e) => return Err(anyhow!("failed to open archive: {}", e)),
-            Ok(f) => f,
-        };
+    /// Remove a partially downloaded archive, together with the ETag it was
+    /// started with.
+    fn remove_partial(part: &Path) {
+        for path in &[part.to_owned(), etag_path(part)] {
+            if !path.is_file() {
+                continue;
+            }
 
-        test_reader_archive(f)
+            if let Err(e) = fs::remove_file(path) {
+                warn!(
+                    "failed to remove partial archive: {}: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
     }
+}
 
-    /// Test that the reader archive is OK.
-    fn test_reader_archive(reader: impl Read) -> Result<()> {
-        use flate2::read::GzDecoder;
-        use tar::Archive;
+/// Test that the given path is a proper archive.
+///
+/// Returns an error describing what's wrong with the archive if it's not OK.
+pub fn test_archive(path: &Path) -> Result<()> {
+    let f = match fs::File::open(path) {
+        Err(e) => return Err(anyhow!("failed to open archive: {}", e)),
+        Ok(f) => f,
+    };
 
-        let mut a = Archive::new(GzDecoder::new(reader));
+    test_reader_archive(f)
+}
+
+/// Test that the reader archive is OK.
+///
+/// The data of every entry is read in full, so that a truncated archive
+/// is detected here rather than once it's being unpacked.
+fn test_reader_archive(reader: impl Read) -> Result<()> {
+    use tar::Archive;
+
+    let mut a = Archive::new(decompress(reader)?);
+
+    let entries = match a.entries() {
+        Err(e) => return Err(anyhow!("failed to list tar entries: {}", e)),
+        Ok(entries) => entries,
+    };
 
-        let entries = match a.entries() {
-            Err(e) => return Err(anyhow!("failed to list tar entries: {}", e)),
-            Ok(entries) => entries,
+    for entry in entries {
+        let mut entry = match entry {
+            Err(e) => return Err(anyhow!("bad entry: {}", e)),
+            Ok(entry) => entry,
         };
 
-        for entry in entries {
-            let entry = match entry {
-                Err(e) => return Err(anyhow!("bad entry: {}", e)),
-                Ok(entry) => entry,
+        let path = match entry.path() {
+            Err(e) => return Err(anyhow!("bad entry: {}", e)),
+            Ok(path) => path.into_owned(),
+        };
+
+        let expected = entry.size();
+
+        let read = io::copy(&mut entry, &mut io::sink())
+            .map_err(|e| anyhow!("failed to read entry: {}: {}", path.display(), e))?;
+
+        if read != expected {
+            return Err(anyhow!(
+                "truncated entry: {}: expected {} bytes but got {}",
+                path.display(),
+                expected,
+                read
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// The result of checking every archive in the cache.
+#[derive(Debug, Default)]
+pub struct CacheCheck {
+    /// Archives which are OK.
+    pub clean: Vec<PathBuf>,
+    /// Archives which are corrupt, and what's wrong with them.
+    pub corrupt: Vec<(PathBuf, String)>,
+}
+
+/// Test every archive in the cache directory, without downloading anything.
+///
+/// Hard links to the same archive, like with `--dedup`, are only tested once
+/// and reported under every path.
+pub fn check_cache(root: &Path) -> Result<CacheCheck> {
+    let mut check = CacheCheck::default();
+
+    for entry in cache_entries(root)? {
+        match test_archive(&entry.paths[0]) {
+            Ok(()) => {
+                for path in entry.paths {
+                    info!("OK: {}", path.display());
+                    check.clean.push(path);
+                }
+            }
+            Err(e) => {
+                for path in entry.paths {
+                    warn!("corrupt: {}: {}", path.display(), e);
+                    check.corrupt.push((path, e.to_string()));
+                }
+            }
+        }
+    }
+
+    Ok(check)
+}
+
+/// An archive in the cache which is a candidate for eviction.
+///
+/// With `--dedup`, an object and the version-named links to it share their
+/// storage, so they're grouped together and evicted as one.
+#[derive(Debug)]
+struct CacheEntry {
+    paths: Vec<PathBuf>,
+    size: u64,
+    used: SystemTime,
+}
+
+/// Get an identifier for the storage of a file, shared by all hard links to
+/// it.
+#[cfg(unix)]
+fn file_id(m: &fs::Metadata, _: &Path) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt as _;
+    (m.dev(), m.ino())
+}
+
+/// Get an identifier for the storage of a file, shared by all hard links to
+/// it.
+#[cfg(not(unix))]
+fn file_id(_: &fs::Metadata, path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// List the archives in the cache directory, including the objects of the
+/// content-addressed store.
+fn cache_entries(root: &Path) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::<CacheEntry>::new();
+    let mut ids = HashMap::new();
+
+    for dir in &[root.to_owned(), root.join("objects")] {
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for e in fs::read_dir(dir)
+            .map_err(|e| anyhow!("failed to read directory: {}: {}", dir.display(), e))?
+        {
+            let e = e.map_err(|e| anyhow!("failed to read entry: {}: {}", dir.display(), e))?;
+            let path = e.path();
+
+            let m = e
+                .metadata()
+                .map_err(|e| anyhow!("failed to read metadata: {}: {}", path.display(), e))?;
+
+            if !m.is_file() {
+                continue;
+            }
+
+            if dir == root {
+                let name = e.file_name();
+                let name = name.to_string_lossy();
+
+                let known = [
+                    ArchiveFormat::TarGz,
+                    ArchiveFormat::TarBz2,
+                    ArchiveFormat::Tar,
+                ]
+                .iter()
+                .any(|f| name.ends_with(&format!(".{}", f.extension())));
+
+                if !known {
+                    continue;
+                }
+            }
+
+            // NB: atime isn't updated on all file systems, so use whichever
+            // is the most recent.
+            let modified = m.modified().ok();
+            let used = match (m.accessed().ok(), modified) {
+                (Some(a), Some(b)) => a.max(b),
+                (a, b) => a.or(b).unwrap_or(UNIX_EPOCH),
             };
 
-            if let Err(e) = entry.path() {
-                return Err(anyhow!("bad entry: {}", e));
+            match ids.entry(file_id(&m, &path)) {
+                hash_map::Entry::Occupied(o) => {
+                    let entry: &mut CacheEntry = &mut entries[*o.get()];
+                    entry.paths.push(path);
+                    entry.used = entry.used.max(used);
+                }
+                hash_map::Entry::Vacant(v) => {
+                    v.insert(entries.len());
+
+                    entries.push(CacheEntry {
+                        paths: vec![path],
+                        size: m.len(),
+                        used,
+                    });
+                }
             }
         }
+    }
+
+    Ok(entries)
+}
+
+/// Test if the start of a response looks like an HTML document, which no
+/// archive does.
+pub fn looks_like_html(data: &[u8]) -> bool {
+    const BOM: &[u8] = b"\xef\xbb\xbf";
+
+    let data = if data.starts_with(BOM) {
+        &data[BOM.len()..]
+    } else {
+        data
+    };
+
+    let start = data
+        .iter()
+        .skip_while(|b| b.is_ascii_whitespace())
+        .take(9)
+        .map(u8::to_ascii_lowercase)
+        .collect::<Vec<_>>();
+
+    start.starts_with(b"<!doctype") || start.starts_with(b"<html")
+}
+
+/// Test if there's enough free space in the cache to start another download.
+///
+/// If there isn't and no downloads are in progress, waiting won't help, so
+/// that's an error.
+fn has_free_space(root: &Path, min_free_space: Option<u64>, idle: bool) -> Result<bool> {
+    let min_free_space = match min_free_space {
+        Some(min_free_space) => min_free_space,
+        None => return Ok(true),
+    };
+
+    let available = fs2::available_space(root)
+        .map_err(|e| anyhow!("failed to get free space: {}: {}", root.display(), e))?;
+
+    if available >= min_free_space {
+        return Ok(true);
+    }
+
+    if idle {
+        return Err(anyhow!(
+            "not enough free space in cache: {}: {} available, but --min-free-space is {}",
+            root.display(),
+            format_bytes(available),
+            format_bytes(min_free_space)
+        ));
+    }
 
-        Ok(())
+    info!(
+        "pausing downloads until running ones are done: {} available in: {}",
+        format_bytes(available),
+        root.display()
+    );
+
+    Ok(false)
+}
+
+/// Remove an archive from the cache, together with its etag sidecar.
+pub fn remove_archive(path: &Path) -> Result<()> {
+    fs::remove_file(path).map_err(|e| anyhow!("failed to remove: {}: {}", path.display(), e))?;
+
+    let etag = etag_path(path);
+
+    if etag.is_file() {
+        fs::remove_file(&etag)
+            .map_err(|e| anyhow!("failed to remove: {}: {}", etag.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Evict the least recently used archives from the cache until it takes up
+/// at most `max_size` bytes.
+///
+/// Archives in `keep` are never evicted, even if that means the cache stays
+/// above the limit.
+pub fn evict_cache(root: &Path, max_size: u64, keep: &HashSet<PathBuf>) -> Result<()> {
+    let mut entries = cache_entries(root)?;
+    let mut total = entries.iter().map(|e| e.size).sum::<u64>();
+
+    if total <= max_size {
+        return Ok(());
     }
+
+    entries.sort_by_key(|e| e.used);
+
+    for entry in entries {
+        if total <= max_size {
+            break;
+        }
+
+        if entry.paths.iter().any(|p| keep.contains(p)) {
+            continue;
+        }
+
+        for path in &entry.paths {
+            info!("evicting: {}", path.display());
+            remove_archive(path)?;
+        }
+
+        total -= entry.size;
+    }
+
+    if total > max_size {
+        warn!(
+            "cache is still {} bytes, above the limit of {} bytes, since its archives are in use",
+            total, max_size
+        );
+    }
+
+    Ok(())
+}
+
+/// Unpack the given archive into `work_dir`, unless it's already unpacked.
+///
+/// Returns the root of the unpacked source tree, as determined by
+/// [source_root].
+pub fn unpack_archive(archive: &Path, work_dir: &Path) -> Result<PathBuf> {
+    use tar::Archive;
+
+    if !work_dir.is_dir() {
+        let f = fs::File::open(archive).map_err(|e| {
+            anyhow!(
+                "failed to open cached archive: {}: {}",
+                archive.display(),
+                e
+            )
+        })?;
+
+        let mut a = Archive::new(
+            decompress(f)
+                .map_err(|e| anyhow!("failed to read archive: {}: {}", archive.display(), e))?,
+        );
+
+        a.unpack(work_dir)
+            .map_err(|e| anyhow!("failed to unpack archive: {}: {}", archive.display(), e))?;
+    }
+
+    source_root(work_dir)
+}
+
+/// Find the root of the source tree in a directory that an archive was
+/// unpacked into.
+///
+/// Most archives contain a single directory like `linux-2.6.39`, in which case
+/// that is the root. Some historic archives have files at the top level or
+/// multiple entries, in which case the directory itself is the root.
+pub fn source_root(dir: &Path) -> Result<PathBuf> {
+    let mut entries = Vec::new();
+
+    for e in fs::read_dir(dir)
+        .map_err(|e| anyhow!("failed to read directory: {}: {}", dir.display(), e))?
+    {
+        let e = e.map_err(|e| anyhow!("failed to read entry: {}: {}", dir.display(), e))?;
+        entries.push(e.path());
+    }
+
+    match entries.as_slice() {
+        [single] if single.is_dir() => Ok(single.clone()),
+        _ => Ok(dir.to_owned()),
+    }
+}
+
+/// Decompress the given archive, detecting the compression from its magic
+/// bytes.
+///
+/// Archives which are neither gzip nor bzip2 compressed are assumed to be
+/// plain tar archives.
+pub fn decompress<'a, R>(mut reader: R) -> io::Result<Box<dyn Read + 'a>>
+where
+    R: 'a + Read,
+{
+    use bzip2::read::BzDecoder;
+    use flate2::read::GzDecoder;
+
+    let mut magic = [0u8; 3];
+    let mut n = 0;
+
+    while n < magic.len() {
+        match reader.read(&mut magic[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+
+    // NB: put back the magic bytes that were read.
+    let reader = io::Cursor::new(magic).take(n as u64).chain(reader);
+
+    Ok(match &magic[..n] {
+        [0x1f, 0x8b, ..] => Box::new(GzDecoder::new(reader)),
+        [b'B', b'Z', b'h'] => Box::new(BzDecoder::new(reader)),
+        _ => Box::new(reader),
+    })
 }
@@ -1,2 +1,29 @@
+pub mod error;
 pub mod git;
+pub mod html;
 pub mod kernels;
+pub mod output;
+pub mod queue;
+pub mod stats;
+pub mod tokei;
+pub mod version;
+
+pub use self::stats::{LanguageStats, Output, OutputMeta, Stat, Timings, TopFile};
+pub use self::tokei::Tokei;
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Analyze an arbitrary directory using the default tokei configuration.
+///
+/// The tag of the output is the name of the directory.
+pub fn analyze_dir(path: &Path) -> Result<Output> {
+    let tag = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => path.display().to_string(),
+    };
+
+    let mut tokei = Tokei::default();
+    tokei.check()?;
+    tokei.analyze(tag, path)
+}
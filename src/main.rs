@@ -1,249 +1,2580 @@
 //! Calculate code statistics for the linux kernel.
 #![deny(missing_docs)]
 
-use anyhow::{anyhow, Context as _, Result};
-use clap::{App, Arg};
-use kernelstats::git::Git;
+use anyhow::{anyhow, Result};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use flate2::Compression;
+use kernelstats::error::Error;
+use kernelstats::git::{sort_semver, Git};
 use kernelstats::kernels::{self, Kernels};
-use log::info;
+use kernelstats::output::{
+    csv_field, existing_output, is_outdated, needs_repair, output_path, raw_tokei_path,
+    read_output, read_output_with, sorted_outputs, write_atomic, write_csv, write_html, write_json,
+    write_plain_json, write_raw_tokei, Format, Layout,
+};
+use kernelstats::queue::{self, analyze_parallel, memory_work_dir, Kernel, WorkDir};
+use kernelstats::stats::{self, default_renames, normalize_languages, MinCode};
+use kernelstats::tokei::{self, Tokei};
+use kernelstats::version::Version;
+use kernelstats::{LanguageStats, Output, Timings};
+use log::{info, warn};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fs;
-use std::ops;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Call tokei on the given path and get statistics.
-fn tokei(dir: &Path) -> Result<HashMap<String, LanguageStats>> {
-    let out = process::Command::new("tokei")
-        .current_dir(dir)
-        .args(&["-o", "json"])
-        .output()?;
+/// What to do when a kernel fails to download or analyze.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnError {
+    /// Stop processing kernels and exit with the error.
+    Abort,
+    /// Keep processing other kernels, and report all failures at the end.
+    Skip,
+    /// Attempt the kernel again a number of times, before skipping it.
+    Retry,
+}
+
+/// How many times a kernel is attempted again with `--on-error retry`, by
+/// default.
+const DEFAULT_RETRIES: usize = 2;
+
+/// A SQLite database that kernel outputs are written to.
+struct Database {
+    connection: rusqlite::Connection,
+}
+
+impl Database {
+    /// Open the database at the given path, creating any missing tables.
+    fn open(path: &Path) -> Result<Database> {
+        let connection = rusqlite::Connection::open(path)
+            .map_err(|e| anyhow!("failed to open database: {}: {}", path.display(), e))?;
+
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS kernel (
+                tag TEXT PRIMARY KEY NOT NULL,
+                analyzed_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS language_stats (
+                tag TEXT NOT NULL REFERENCES kernel (tag),
+                language TEXT NOT NULL,
+                blanks INTEGER NOT NULL,
+                code INTEGER NOT NULL,
+                comments INTEGER NOT NULL,
+                files INTEGER NOT NULL,
+                PRIMARY KEY (tag, language)
+            );",
+        )?;
+
+        Ok(Database { connection })
+    }
+
+    /// Test if the database already has statistics for the given kernel.
+    fn contains(&self, tag: &str) -> Result<bool> {
+        use rusqlite::OptionalExtension as _;
+
+        let row = self
+            .connection
+            .query_row(
+                "SELECT 1 FROM kernel WHERE tag = ?",
+                rusqlite::params![tag],
+                |_| Ok(()),
+            )
+            .optional()?;
+
+        Ok(row.is_some())
+    }
+
+    /// Remove any existing statistics for the given kernel.
+    fn remove(&mut self, tag: &str) -> Result<()> {
+        let tx = self.connection.transaction()?;
+        tx.execute(
+            "DELETE FROM language_stats WHERE tag = ?",
+            rusqlite::params![tag],
+        )?;
+        tx.execute("DELETE FROM kernel WHERE tag = ?", rusqlite::params![tag])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Insert the given output, with one row per language.
+    fn insert(&mut self, output: &Output) -> Result<()> {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let analyzed_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let tx = self.connection.transaction()?;
+
+        tx.execute(
+            "INSERT INTO kernel (tag, analyzed_at) VALUES (?, ?)",
+            rusqlite::params![output.tag, analyzed_at],
+        )?;
+
+        for (language, stats) in &output.all {
+            tx.execute(
+                "INSERT INTO language_stats (tag, language, blanks, code, comments, files)
+                VALUES (?, ?, ?, ?, ?, ?)",
+                rusqlite::params![
+                    output.tag,
+                    language,
+                    stats.blanks as i64,
+                    stats.code as i64,
+                    stats.comments as i64,
+                    stats.files as i64
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// How many times to attempt posting an output.
+const POST_ATTEMPTS: u64 = 3;
+
+/// POST the given output as JSON to the given URL, retrying on server errors.
+async fn post_output(client: &reqwest::Client, url: &str, output: &Output) -> Result<()> {
+    let body = serde_json::to_vec(output).map_err(|e| anyhow!("failed to serialize: {}", e))?;
+
+    let mut attempt = 1;
+
+    loop {
+        let res = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(|e| anyhow!("failed to post output: {}: {}", url, e))?;
+
+        let status = res.status();
+
+        if status.is_success() {
+            return Ok(());
+        }
+
+        if !status.is_server_error() || attempt >= POST_ATTEMPTS {
+            return Err(anyhow!("failed to post output: {}: {}", url, status));
+        }
+
+        warn!(
+            "failed to post output: {}: {}: retrying ({}/{})",
+            url, status, attempt, POST_ATTEMPTS
+        );
+
+        tokio::time::delay_for(Duration::from_secs(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Totals of a single kernel, as written to the summary.
+#[derive(Debug, Serialize)]
+struct Summary {
+    /// The tag of the kernel.
+    tag: String,
+    /// Totals across all languages.
+    total: LanguageStats,
+    /// Totals for each language.
+    languages: BTreeMap<String, LanguageStats>,
+}
+
+impl Summary {
+    /// Summarize the given output, discarding per-file statistics.
+    ///
+    /// Languages are renamed according to `renames`, so that outputs produced
+    /// by different versions of tokei line up. Languages with too few lines
+    /// of code are dropped according to `min_code`, also for outputs which
+    /// were produced without it.
+    fn new(
+        output: Output,
+        renames: &HashMap<String, String>,
+        min_code: Option<MinCode>,
+    ) -> Summary {
+        let mut total = LanguageStats::default();
+        let mut languages = BTreeMap::new();
+        let mut all = normalize_languages(output.all, renames);
+
+        if let Some(min_code) = min_code {
+            min_code.apply(&mut all);
+        }
+
+        for (language, mut stats) in all {
+            stats.stats.clear();
+            stats.children.clear();
+            total += stats.clone();
+            languages.insert(language, stats);
+        }
+
+        Summary {
+            tag: output.tag,
+            total,
+            languages,
+        }
+    }
+}
+
+/// Parse the `--min-code` and `--other` options.
+fn min_code(matches: &ArgMatches) -> Result<Option<MinCode>> {
+    let code = match matches.value_of("min-code") {
+        Some(n) => str::parse(n).map_err(|e| anyhow!("failed to parse min code: {}", e))?,
+        None => return Ok(None),
+    };
+
+    Ok(Some(MinCode {
+        code,
+        other: matches.is_present("other"),
+    }))
+}
+
+/// Normalize a `--path` to the form that paths inside of the kernel tree are
+/// compared in, like `drivers/net` for `./drivers/net/`.
+///
+/// Absolute paths and paths which aren't inside of the kernel tree are
+/// rejected.
+fn normalize_path(path: &str) -> Result<String> {
+    use std::path::Component;
+
+    let bad = || {
+        anyhow!(
+            "bad --path: {}: expected a directory inside of the kernel tree",
+            path
+        )
+    };
+
+    let mut parts = Vec::new();
+
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(part) => parts.push(part.to_str().ok_or_else(bad)?),
+            Component::CurDir => {}
+            _ => return Err(bad()),
+        }
+    }
+
+    if parts.is_empty() {
+        return Err(bad());
+    }
+
+    Ok(parts.join("/"))
+}
+
+/// Parse the `--compression-level` option.
+fn compression(matches: &ArgMatches) -> Result<Compression> {
+    let level = match matches.value_of("compression-level") {
+        Some(level) => level,
+        None => return Ok(Compression::default()),
+    };
+
+    let level = str::parse::<u32>(level)
+        .ok()
+        .filter(|level| *level <= 9)
+        .ok_or_else(|| anyhow!("bad --compression-level: {}: expected 0 to 9", level))?;
+    Ok(Compression::new(level))
+}
+
+/// Write a summary of all the given outputs to the given path, in release
+/// order.
+fn write_summary(
+    path: &Path,
+    outputs: &[PathBuf],
+    renames: &HashMap<String, String>,
+    min_code: Option<MinCode>,
+) -> Result<()> {
+    let mut summaries = Vec::new();
+
+    for p in outputs {
+        summaries.push(Summary::new(read_output(p)?, renames, min_code));
+    }
+
+    summaries.sort_by_key(|s| Version::parse(&s.tag));
+    write_summaries(path, &summaries)
+}
+
+/// Write the given summaries to the given path.
+fn write_summaries(path: &Path, summaries: &[Summary]) -> Result<()> {
+    write_atomic(path, |mut o| {
+        serde_json::to_writer_pretty(&mut o, summaries)
+            .map_err(|e| anyhow!("failed to serialize summary: {}", e))?;
+        Ok(o)
+    })
+}
+
+/// Summarize all outputs in the stats directory, in release order.
+///
+/// Outputs which can't be read are skipped with a warning.
+fn read_summaries(
+    stats_dir: &Path,
+    renames: &HashMap<String, String>,
+    min_code: Option<MinCode>,
+    migrate: Option<Compression>,
+) -> Result<Vec<Summary>> {
+    let mut summaries = Vec::new();
+
+    for p in sorted_outputs(stats_dir)? {
+        match read_output_with(&p, migrate) {
+            Ok(output) => summaries.push(Summary::new(output, renames, min_code)),
+            Err(e) => warn!("skipping: {}: {:#}", p.display(), e),
+        }
+    }
+
+    Ok(summaries)
+}
+
+/// A single record of the summary in the jsonl format.
+#[derive(Debug, Serialize)]
+struct SummaryRecord<'a> {
+    /// The tag of the kernel.
+    version: &'a str,
+    /// The version of tokei the statistics were produced with.
+    tokei_version: &'a str,
+    /// The language the statistics are for.
+    language: &'a str,
+    /// Totals for the language.
+    stats: &'a LanguageStats,
+}
+
+/// Write the summary of all outputs in the stats directory as JSON lines, with
+/// one record per kernel and language, in release order.
+///
+/// Outputs are read one at a time, so memory use doesn't grow with the number
+/// of kernels. Outputs which can't be read are skipped with a warning.
+fn summarize_jsonl(
+    stats_dir: &Path,
+    path: &Path,
+    renames: &HashMap<String, String>,
+    min_code: Option<MinCode>,
+    migrate: Option<Compression>,
+) -> Result<()> {
+    use std::io::Write;
+
+    let outputs = sorted_outputs(stats_dir)?;
+
+    info!(
+        "writing summary of {} kernel(s): {}",
+        outputs.len(),
+        path.display()
+    );
+
+    write_atomic(path, |o| {
+        let mut o = std::io::BufWriter::new(o);
+
+        for p in outputs {
+            let output = match read_output_with(&p, migrate) {
+                Ok(output) => output,
+                Err(e) => {
+                    warn!("skipping: {}: {:#}", p.display(), e);
+                    continue;
+                }
+            };
+
+            let tokei_version = output.tokei_version.clone();
+            let summary = Summary::new(output, renames, min_code);
+
+            for (language, stats) in &summary.languages {
+                let record = SummaryRecord {
+                    version: &summary.tag,
+                    tokei_version: &tokei_version,
+                    language,
+                    stats,
+                };
+
+                serde_json::to_writer(&mut o, &record)
+                    .map_err(|e| anyhow!("failed to serialize summary: {}", e))?;
+                writeln!(o)?;
+            }
+        }
+
+        o.into_inner()
+            .map_err(|e| anyhow!("failed to flush: {}: {}", path.display(), e.error()))
+    })
+}
+
+/// Regenerate the summary from all outputs in the stats directory, without
+/// analyzing anything.
+fn summarize(
+    stats_dir: &Path,
+    path: &Path,
+    renames: &HashMap<String, String>,
+    min_code: Option<MinCode>,
+    migrate: Option<Compression>,
+) -> Result<()> {
+    let summaries = read_summaries(stats_dir, renames, min_code, migrate)?;
+
+    info!(
+        "writing summary of {} kernel(s): {}",
+        summaries.len(),
+        path.display()
+    );
+    write_summaries(path, &summaries)
+}
+
+/// Write the lines of code of each language for every kernel in the stats
+/// directory as CSV, with one row per kernel in release order and one column
+/// per language.
+///
+/// Languages which are missing from a kernel are written as 0.
+fn timeseries(
+    stats_dir: &Path,
+    path: &Path,
+    renames: &HashMap<String, String>,
+    min_code: Option<MinCode>,
+    migrate: Option<Compression>,
+) -> Result<()> {
+    use std::collections::BTreeSet;
+    use std::io::Write;
+
+    let summaries = read_summaries(stats_dir, renames, min_code, migrate)?;
+
+    let languages = summaries
+        .iter()
+        .flat_map(|s| s.languages.keys())
+        .collect::<BTreeSet<_>>();
+
+    info!(
+        "writing time series of {} kernel(s): {}",
+        summaries.len(),
+        path.display()
+    );
+
+    write_atomic(path, |o| {
+        let mut o = std::io::BufWriter::new(o);
+
+        write!(o, "tag")?;
+
+        for language in &languages {
+            write!(o, ",{}", csv_field(&format!("code_{}", language)))?;
+        }
+
+        writeln!(o)?;
+
+        for summary in &summaries {
+            write!(o, "{}", csv_field(&summary.tag))?;
+
+            for language in &languages {
+                let code = summary.languages.get(*language).map(|s| s.code);
+                write!(o, ",{}", code.unwrap_or(0))?;
+            }
+
+            writeln!(o)?;
+        }
+
+        o.into_inner()
+            .map_err(|e| anyhow!("failed to flush: {}: {}", path.display(), e.error()))
+    })
+}
+
+/// Escape a Prometheus label value.
+fn prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write metrics for all the given outputs as a Prometheus textfile, as read
+/// by the node_exporter textfile collector.
+fn write_prometheus(path: &Path, outputs: &[PathBuf]) -> Result<()> {
+    use std::io::Write;
+
+    let mut outputs = outputs
+        .iter()
+        .map(|p| read_output(p))
+        .collect::<Result<Vec<_>>>()?;
+
+    outputs.sort_by_key(|o| Version::parse(&o.tag));
+
+    /// The name, help, and value of a metric.
+    type Metric = (&'static str, &'static str, fn(&LanguageStats) -> f64);
+
+    let metrics: [Metric; 5] = [
+        ("kernel_code_lines", "Lines of code.", |s| s.code as f64),
+        ("kernel_comment_lines", "Lines of comments.", |s| {
+            s.comments as f64
+        }),
+        ("kernel_blank_lines", "Blank lines.", |s| s.blanks as f64),
+        ("kernel_files", "Number of files.", |s| s.files as f64),
+        (
+            "kernel_comment_ratio",
+            "Lines of comments per line of code.",
+            |s| s.comment_ratio,
+        ),
+    ];
+
+    write_atomic(path, |o| {
+        let mut o = std::io::BufWriter::new(o);
+
+        for (name, help, value) in &metrics {
+            writeln!(o, "# HELP {} {}", name, help)?;
+            writeln!(o, "# TYPE {} gauge", name)?;
+
+            for output in &outputs {
+                let mut languages = output.all.iter().collect::<Vec<_>>();
+                languages.sort_by(|a, b| a.0.cmp(b.0));
+
+                for (language, stats) in languages {
+                    writeln!(
+                        o,
+                        "{}{{version=\"{}\",language=\"{}\"}} {}",
+                        name,
+                        prometheus_label(&output.tag),
+                        prometheus_label(language),
+                        value(stats)
+                    )?;
+                }
+            }
+        }
+
+        o.into_inner()
+            .map_err(|e| anyhow!("failed to flush: {}: {}", path.display(), e.error()))
+    })
+}
+
+/// A manifest of stats files and their checksums, which can later be used to
+/// verify that the files haven't changed.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// The stats files, sorted by path.
+    files: Vec<ManifestEntry>,
+}
+
+/// A single stats file in the manifest.
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// Path of the file, relative to the stats directory.
+    path: PathBuf,
+    /// The SHA256 checksum of the file, in hex.
+    sha256: String,
+    /// The version of tokei the statistics were produced with.
+    tokei_version: String,
+}
+
+/// Write a manifest of all the given outputs in the stats directory to the
+/// given path.
+fn write_manifest(path: &Path, stats_dir: &Path, outputs: &[PathBuf]) -> Result<()> {
+    let mut files = Vec::new();
+
+    for p in outputs {
+        let output = read_output(p)?;
+
+        files.push(ManifestEntry {
+            path: p.strip_prefix(stats_dir).unwrap_or(p).to_path_buf(),
+            sha256: kernels::sha256_file(p)?,
+            tokei_version: output.tokei_version,
+        });
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let manifest = Manifest { files };
+
+    write_atomic(path, |o| {
+        serde_json::to_writer_pretty(&o, &manifest)
+            .map_err(|e| anyhow!("failed to serialize manifest: {}: {}", path.display(), e))?;
+        Ok(o)
+    })
+}
+
+/// Verify that the files in the stats directory match the checksums in the
+/// given manifest, reporting every file that changed.
+fn verify_manifest(stats_dir: &Path, path: &Path) -> Result<()> {
+    let f = fs::File::open(path)
+        .map_err(|e| anyhow!("failed to open manifest: {}: {}", path.display(), e))?;
+    let manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(f))
+        .map_err(|e| anyhow!("failed to deserialize manifest: {}: {}", path.display(), e))?;
+
+    let mut changed = 0;
+
+    for entry in &manifest.files {
+        let p = stats_dir.join(&entry.path);
+
+        if !p.is_file() {
+            println!("missing: {}", p.display());
+            changed += 1;
+            continue;
+        }
+
+        if kernels::sha256_file(&p)? != entry.sha256 {
+            println!("changed: {}", p.display());
+            changed += 1;
+        }
+    }
+
+    if changed > 0 {
+        return Err(anyhow!(
+            "{} of {} file(s) don't match the manifest: {}",
+            changed,
+            manifest.files.len(),
+            path.display()
+        ));
+    }
+
+    info!("{} file(s) match the manifest", manifest.files.len());
+    Ok(())
+}
+
+/// Regenerate the index of releases at `out` from the directory listings of
+/// the given mirror.
+///
+/// Annotations like `important`, `path` and `skip` are preserved from the
+/// existing index, which is read from `kernels_file`, or `out` if it exists.
+async fn update_index(
+    client: &reqwest::Client,
+    mirror: &str,
+    kernels_file: Option<&Path>,
+    out: &Path,
+) -> Result<()> {
+    use std::io::Write;
+
+    let mut kernels = match kernels_file {
+        Some(path) => kernels::kernels_from_file(path)?,
+        None if out.is_file() => kernels::kernels_from_file(out)?,
+        None => kernels::kernels()?,
+    };
+
+    let scraped = kernels::scrape_mirror(client, mirror).await?;
+    let added = kernels::merge_releases(&mut kernels.releases, scraped);
+    kernels::validate_releases(&kernels.releases)?;
+
+    let yaml = serde_yaml::to_string(&kernels)
+        .map_err(|e| anyhow!("failed to serialize kernels: {}", e))?;
+
+    // NB: the document marker is stripped to match the embedded index.
+    let yaml = yaml.strip_prefix("---\n").unwrap_or(&yaml);
+
+    write_atomic(out, |mut o| {
+        writeln!(o, "{}", yaml.trim_end())?;
+        Ok(o)
+    })?;
+
+    info!("{} new release(s) from: {}", added, mirror);
+    Ok(())
+}
+
+/// Write the URL that each release resolves to on each mirror, one `version
+/// url` pair per line, in the order the releases are downloaded.
+fn dump_urls(
+    path: &Path,
+    releases: &[kernels::KernelRelease],
+    mirrors: &[String],
+    sources: &[kernels::Source],
+) -> Result<()> {
+    use std::io::Write;
+
+    write_atomic(path, |o| {
+        let mut o = std::io::BufWriter::new(o);
+
+        for release in releases {
+            for mirror in release.mirrors(mirrors, sources) {
+                writeln!(o, "{} {}", release, release.archive_url(mirror)?)?;
+            }
+        }
+
+        o.into_inner()
+            .map_err(|e| anyhow!("failed to flush: {}: {}", path.display(), e.error()))
+    })
+}
+
+/// Download the given releases into the cache directory and report how many
+/// of them were downloaded, without analyzing anything.
+#[allow(clippy::too_many_arguments)]
+async fn download_only(
+    client: &reqwest::Client,
+    cache_dir: &Path,
+    releases: &[kernels::KernelRelease],
+    mirrors: &[String],
+    sources: &[kernels::Source],
+    verify: bool,
+    revalidate: bool,
+    parallelism: usize,
+    keep_going: bool,
+    retries: usize,
+    dedup: bool,
+    max_bandwidth: Option<u64>,
+    min_free_space: Option<u64>,
+) -> Result<()> {
+    let preexisting = releases
+        .iter()
+        .map(|r| r.cache_path(cache_dir))
+        .filter(|p| p.is_file())
+        .collect::<HashSet<_>>();
+
+    if !cache_dir.is_dir() {
+        fs::create_dir_all(cache_dir).map_err(|e| {
+            anyhow!(
+                "failed to create cache directory: {}: {}",
+                cache_dir.display(),
+                e
+            )
+        })?;
+    }
+
+    info!("downloading old kernels to: {}", cache_dir.display());
+
+    let downloaded = kernels::download_old_kernels(
+        client,
+        cache_dir,
+        releases,
+        mirrors,
+        sources,
+        verify,
+        revalidate,
+        parallelism,
+        keep_going,
+        retries,
+        dedup,
+        max_bandwidth,
+        min_free_space,
+    )
+    .await?;
+
+    let cached = downloaded
+        .cached
+        .iter()
+        .filter(|k| preexisting.contains(&k.version.cache_path(cache_dir)))
+        .count();
+
+    println!(
+        "{} downloaded, {} already cached",
+        downloaded.cached.len() - cached,
+        cached
+    );
+
+    if !downloaded.failed.is_empty() {
+        eprintln!("{} kernel(s) failed:", downloaded.failed.len());
+
+        for (version, e) in &downloaded.failed {
+            eprintln!("  {}: {:#}", version, e);
+        }
+
+        return Err(anyhow!(
+            "{} kernel(s) failed to download",
+            downloaded.failed.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Test every archive in the cache directory, failing if any of them is
+/// corrupt.
+fn check_cache(cache_dir: &Path) -> Result<()> {
+    if !cache_dir.is_dir() {
+        return Err(anyhow!("missing cache directory: {}", cache_dir.display()));
+    }
+
+    let check = kernels::check_cache(cache_dir)?;
+
+    for (path, e) in &check.corrupt {
+        println!("corrupt: {}: {}", path.display(), e);
+    }
+
+    println!(
+        "{} clean, {} corrupt archive(s) in: {}",
+        check.clean.len(),
+        check.corrupt.len(),
+        cache_dir.display()
+    );
+
+    if !check.corrupt.is_empty() {
+        return Err(anyhow!("{} corrupt archive(s)", check.corrupt.len()));
+    }
+
+    Ok(())
+}
+
+/// Read the output for the given kernel tag from the stats directory.
+fn read_tag_output(stats_dir: &Path, tag: &str) -> Result<Output> {
+    let path = existing_output(stats_dir, Layout::Flat, tag, Format::Json)
+        .or_else(|| existing_output(stats_dir, Layout::Nested, tag, Format::Json));
+
+    let path = match path {
+        Some(path) => path,
+        None => {
+            return Err(anyhow!(
+                "missing statistics for {}: {}: run the analysis for that tag first",
+                tag,
+                output_path(stats_dir, Layout::Flat, tag, Format::Json).display()
+            ));
+        }
+    };
+
+    read_output(&path)
+}
+
+/// Print a per-language table of how statistics changed between the two
+/// kernels in the given `{from}..{to}` range.
+fn diff(stats_dir: &Path, range: &str) -> Result<()> {
+    let mut it = range.splitn(2, "..");
+
+    let (from, to) = match (it.next(), it.next()) {
+        (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => (from, to),
+        _ => {
+            return Err(anyhow!(
+                "expected range like `v6.0..v6.6`, but got: {}",
+                range
+            ))
+        }
+    };
+
+    let from = read_tag_output(stats_dir, &normalize_tag(from))?;
+    let to = read_tag_output(stats_dir, &normalize_tag(to))?;
+
+    let mut languages = from.all.keys().chain(to.all.keys()).collect::<Vec<_>>();
+    languages.sort();
+    languages.dedup();
+
+    println!("{} -> {}", from.tag, to.tag);
+    println!(
+        "{:<32} {:>12} {:>12} {:>12}",
+        "language", "code", "comments", "blanks"
+    );
+
+    let empty = LanguageStats::default();
+
+    for language in languages {
+        let a = from.all.get(language).unwrap_or(&empty);
+        let b = to.all.get(language).unwrap_or(&empty);
+
+        println!(
+            "{:<32} {:>+12} {:>+12} {:>+12}",
+            language,
+            b.code as i64 - a.code as i64,
+            b.comments as i64 - a.comments as i64,
+            b.blanks as i64 - a.blanks as i64,
+        );
+    }
+
+    Ok(())
+}
+
+/// Read the kernels to process from the given file, with one version or tag
+/// per line. Versions like `6.6` are normalized to tags like `v6.6`.
+///
+/// Empty lines and lines starting with `#` are ignored.
+fn read_jobs(path: &Path) -> Result<Vec<String>> {
+    let jobs = fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read jobs: {}: {}", path.display(), e))?;
+
+    let jobs = jobs
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            if line.starts_with(|c: char| c.is_ascii_digit()) {
+                normalize_tag(line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if jobs.is_empty() {
+        return Err(anyhow!("no kernels in jobs file: {}", path.display()));
+    }
+
+    Ok(jobs)
+}
+
+/// Tags are always stored with a leading `v`, but allow it to be omitted.
+fn normalize_tag(tag: &str) -> String {
+    if tag.starts_with('v') {
+        tag.to_string()
+    } else {
+        format!("v{}", tag)
+    }
+}
+
+/// Initialize logging in the given format, either `pretty` or `json`.
+///
+/// The json format emits one object per line, for consumption by log
+/// collectors.
+fn init_logger(format: &str) -> Result<()> {
+    match format {
+        "pretty" => pretty_env_logger::init(),
+        "json" => {
+            use std::io::Write as _;
+
+            env_logger::Builder::from_default_env()
+                .format(|buf, record| {
+                    let line = serde_json::json!({
+                        "level": record.level().to_string(),
+                        "timestamp": buf.timestamp_millis().to_string(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    });
+
+                    writeln!(buf, "{}", line)
+                })
+                .init();
+        }
+        format => return Err(anyhow!("unsupported log format: {}", format)),
+    }
+
+    Ok(())
+}
+
+/// Flags which select or post-process many kernels, and therefore can't be
+/// used when analyzing a single kernel.
+const BATCH_FLAGS: &[&str] = &[
+    "verify",
+    "dry-run",
+    "keep-going",
+    "on-error",
+    "retries",
+    "force",
+    "outdated",
+    "all",
+    "limit",
+    "include-rc",
+    "rc-only",
+    "since",
+    "until",
+    "summary",
+    "prometheus",
+    "manifest",
+    "jobs-from",
+    "branch",
+    "local-dir",
+    "local-tar",
+    "local-tag",
+];
+
+/// The default configuration file, which is used if it's present.
+const DEFAULT_CONFIG: &str = "kernelstats.toml";
+
+/// Defaults loaded from a configuration file.
+///
+/// Flags given on the command line take precedence over these.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// The cache directory.
+    cache: Option<PathBuf>,
+    /// The work directory.
+    work: Option<PathBuf>,
+    /// The stats directory.
+    stats: Option<PathBuf>,
+    /// How many downloads to perform in parallel.
+    parallelism: Option<usize>,
+    /// Mirrors to download from, in order of preference.
+    mirror: Vec<String>,
+    /// Additional paths to exclude.
+    exclude: Vec<String>,
+    /// Languages to rename, in addition to the built-in renames.
+    rename: HashMap<String, String>,
+}
+
+impl Config {
+    /// Load configuration from the given path, or from the default path if
+    /// it's present.
+    fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG).is_file() => Path::new(DEFAULT_CONFIG),
+            None => return Ok(Config::default()),
+        };
+
+        let config = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read config: {}: {}", path.display(), e))?;
+
+        toml::from_str(&config)
+            .map_err(|e| anyhow!("failed to parse config: {}: {}", path.display(), e))
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("kernelstats")
+        .version("0.0.1")
+        .author("John-John Tedro <udoprog@tedro.se>")
+        .about("Calculates statistics across kernel releases.")
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("The format to log in, either `pretty` or `json`.")
+                .possible_values(&["pretty", "json"])
+                .default_value("pretty"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Load defaults from the given TOML file, instead of `kernelstats.toml`.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("Verify that all kernels are available."),
+        )
+        .arg(
+            Arg::with_name("revalidate")
+                .long("revalidate")
+                .help("Check cached archives against the mirror, and download them again if they've changed upstream."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("Print what would be downloaded and analyzed, without doing it."),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .long("keep-going")
+                .help("Keep processing other kernels if one fails, and report all failures at the end."),
+        )
+        .arg(
+            Arg::with_name("on-error")
+                .long("on-error")
+                .value_name("MODE")
+                .help("What to do when a kernel fails: abort exits with the error, which is the default, skip is like --keep-going, and retry attempts the kernel again before skipping it.")
+                .takes_value(true)
+                .possible_values(&["abort", "skip", "retry"])
+                .conflicts_with("keep-going"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .value_name("N")
+                .help("How many times to attempt a failing kernel again with --on-error retry [default: 2].")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Analyze selected kernels even if statistics already exist, overwriting them."),
+        )
+        .arg(
+            Arg::with_name("outdated")
+                .long("outdated")
+                .help("Analyze selected kernels whose statistics were produced by a different version of tokei."),
+        )
+        .arg(
+            Arg::with_name("all")
+                .long("all")
+                .help("Build all kernel versions, not just important."),
+        )
+        .arg(
+            Arg::with_name("limit")
+                .long("limit")
+                .value_name("N")
+                .help("Only process the first N selected kernels.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("include-rc")
+                .long("include-rc")
+                .help("Also process release candidates, which are skipped by default.")
+                .conflicts_with("rc-only"),
+        )
+        .arg(
+            Arg::with_name("rc-only")
+                .long("rc-only")
+                .help("Only process release candidates."),
+        )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .value_name("VERSION")
+                .help("Only process kernels at or after the given version, like `v5.0`.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("until")
+                .long("until")
+                .value_name("VERSION")
+                .help("Only process kernels at or before the given version, like `v6.6`.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .value_name("GLOB")
+                .help("Exclude paths matching the given glob from the analysis. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("tokei-bin")
+                .long("tokei-bin")
+                .value_name("PATH")
+                .env("KERNELSTATS_TOKEI")
+                .help("The tokei program to use for analysis.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tokei-timeout")
+                .long("tokei-timeout")
+                .value_name("SECS")
+                .help("How many seconds tokei may run for a single kernel before it's killed.")
+                .takes_value(true)
+                .default_value("600"),
+        )
+        .arg(
+            Arg::with_name("only-language")
+                .long("only-language")
+                .value_name("NAME")
+                .help("Only keep statistics for the given language, like `C` or `Rust`. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("min-code")
+                .long("min-code")
+                .value_name("N")
+                .help("Drop languages with fewer than N lines of code from the outputs and the summary.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("other")
+                .long("other")
+                .help("Sum up the languages dropped by --min-code under `Other`, instead of discarding them.")
+                .requires("min-code"),
+        )
+        .arg(
+            Arg::with_name("path")
+                .long("path")
+                .value_name("SUBDIR")
+                .help("Only check out and analyze the given directory of each kernel, like `drivers/net`. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("extensions")
+                .long("extensions")
+                .value_name("LIST")
+                .help("Also count lines by file extension regardless of language, like `c,h,rs`, in the `by_extension` section of the output.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("follow-symlinks")
+                .long("follow-symlinks")
+                .help("Also analyze the files and directories that symbolic links point to, as if they were where the links are. By default, tokei skips symbolic links."),
+        )
+        .arg(
+            Arg::with_name("subsystem-report")
+                .long("subsystem-report")
+                .value_name("NAME")
+                .help("Also report on a curated selection of files in the `reports` section of the output, like `rust` for the `rust/` directory and every `.rs` file. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("top-files")
+                .long("top-files")
+                .value_name("N")
+                .help("List the N files with the most lines of code in each output.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tokei-arg")
+                .long("tokei-arg")
+                .value_name("ARG")
+                .help("Extra argument to pass to tokei. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .value_name("DIR")
+                .help("Sets the path to the cache directory.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-max-size")
+                .long("cache-max-size")
+                .value_name("BYTES")
+                .help("Evict the least recently used archives once the cache grows above this size.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discard-archives")
+                .long("discard-archives")
+                .help("Remove archives downloaded in this run from the cache once they've been analyzed."),
+        )
+        .arg(
+            Arg::with_name("discard-all")
+                .long("discard-all")
+                .help("With --discard-archives, also remove archives which were cached before this run.")
+                .requires("discard-archives"),
+        )
+        .arg(
+            Arg::with_name("dedup")
+                .long("dedup")
+                .help("Store downloaded archives by their SHA256 under `objects/` in the cache directory."),
+        )
+        .arg(
+            Arg::with_name("work")
+                .long("work")
+                .value_name("DIR")
+                .help("Sets the path to the work directory.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("work-in-memory")
+                .long("work-in-memory")
+                .value_name("BYTES")
+                .help("Unpack kernels into a directory in memory instead of the work directory on Linux, if it has room for at least this many bytes.")
+                .takes_value(true)
+                .conflicts_with_all(&["keep-work", "incremental"]),
+        )
+        .arg(
+            Arg::with_name("keep-work")
+                .long("keep-work")
+                .help("Don't remove unpacked kernels from the work directory, for debugging."),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .value_name("DIR")
+                .help("Directory to store statistics in.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("jobs-from")
+                .long("jobs-from")
+                .value_name("FILE")
+                .help("Only process the kernels listed in the given file, with one version or tag per line.")
+                .takes_value(true)
+                .conflicts_with_all(&["all", "since", "until", "include-rc", "rc-only", "local-dir", "local-tar"]),
+        )
+        .arg(
+            Arg::with_name("kernels-file")
+                .long("kernels-file")
+                .value_name("PATH")
+                .help("Read the list of released kernels from the given YAML file, instead of the built-in one.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .long("output-dir")
+                .value_name("DIR")
+                .help("Directory to store statistics in, grouped by major version like `v6.x/linux-v6.6.json.gz`. Overrides --stats.")
+                .takes_value(true)
+                .conflicts_with("stats"),
+        )
+        .arg(
+            Arg::with_name("compression-level")
+                .long("compression-level")
+                .value_name("LEVEL")
+                .help("The gzip compression level of json outputs, from 0 for none to 9 for the smallest files [default: 6].")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-compress")
+                .long("no-compress")
+                .help("Write json outputs uncompressed, like `linux-v6.6.json`.")
+                .conflicts_with("compression-level"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("Format to write statistics in.")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .default_value("json"),
+        )
+        .arg(
+            Arg::with_name("keep-raw-tokei")
+                .long("keep-raw-tokei")
+                .help("Keep the unmodified output of tokei next to the output, like `linux-v6.6.tokei.json.gz`.")
+                .conflicts_with("sqlite"),
+        )
+        .arg(
+            Arg::with_name("sqlite")
+                .long("sqlite")
+                .value_name("PATH")
+                .help("Write statistics into the given SQLite database instead of the stats directory.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .value_name("PATH")
+                .help("Write a summary of all analyzed kernels to the given path.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("prometheus")
+                .long("prometheus")
+                .value_name("PATH")
+                .help("Write metrics for all analyzed kernels to the given Prometheus textfile.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump-urls")
+                .long("dump-urls")
+                .value_name("FILE")
+                .help("Write the URL that each selected release resolves to on each mirror to the given file.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .value_name("PATH")
+                .help("Write a manifest with the checksum of every stats file to the given path.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("html")
+                .long("html")
+                .value_name("PATH")
+                .help("Write the statistics of the last analyzed kernel as a standalone HTML page.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("post-url")
+                .long("post-url")
+                .value_name("URL")
+                .help("POST the JSON of each analyzed kernel to the given URL.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("parallelism")
+                .long("parallelism")
+                .short("p")
+                .value_name("<count>")
+                .help("How many downloads and analyses to perform in parallel.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("download-parallelism")
+                .long("download-parallelism")
+                .value_name("<count>")
+                .help("How many downloads to perform in parallel, overriding --parallelism.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("analysis-parallelism")
+                .long("analysis-parallelism")
+                .value_name("<count>")
+                .help("How many kernels to analyze in parallel, overriding --parallelism.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-free-space")
+                .long("min-free-space")
+                .value_name("BYTES")
+                .help("Don't start new downloads while the cache has less than this many bytes of free space.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-bandwidth")
+                .long("max-bandwidth")
+                .value_name("BYTES_PER_SEC")
+                .help("Limit the combined throughput of all downloads.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mirror")
+                .long("mirror")
+                .value_name("URL")
+                .env("KERNELSTATS_MIRROR")
+                .help("Base URL of a mirror to download kernels from. Can be specified multiple times, in which case each mirror is tried in order.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .long("proxy")
+                .value_name("URL")
+                .help("Proxy to use for downloads, instead of the proxies configured through HTTP_PROXY, HTTPS_PROXY, and NO_PROXY.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("SECS")
+                .help("How many seconds to wait when connecting to a mirror.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("SECS")
+                .help("How many seconds a single download may take in total.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel-git")
+                .long("kernel-git")
+                .value_name("DIR")
+                .help("Sets the path to a kernel git directory.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel-remote")
+                .long("kernel-remote")
+                .value_name("URL")
+                .help("Shallowly clone tags from the given remote if the kernel git directory is missing. A shallow clone has no history between tags, so commit counts are left out and --churn is refused.")
+                .takes_value(true)
+                .requires("kernel-git"),
+        )
+        .arg(
+            Arg::with_name("branch")
+                .long("branch")
+                .value_name("PATTERN")
+                .help("Also analyze the head of every branch matching the given pattern, like `linux-5.15.y`, from --kernel-remote or otherwise --kernel-git. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("kernel-git"),
+        )
+        .arg(
+            Arg::with_name("git-config")
+                .long("git-config")
+                .value_name("KEY=VALUE")
+                .help("Configuration to pass to every invocation of git, like `core.sshCommand=ssh -i key`. Can be specified multiple times.")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("incremental")
+                .long("incremental")
+                .help("Check out tags one after another in a single worktree, which is kept in the work directory between runs.")
+                .requires("kernel-git"),
+        )
+        .arg(
+            Arg::with_name("churn")
+                .long("churn")
+                .help("Also compute statistics for only the files which changed since the previous tag, in the `churn` section of the output.")
+                .requires("kernel-git"),
+        )
+        .arg(
+            Arg::with_name("validate-lineage")
+                .long("validate-lineage")
+                .help("Warn about tags whose previous tag in version order isn't an ancestor of them in git.")
+                .requires("kernel-git")
+                .conflicts_with("lazy-fetch"),
+        )
+        .arg(
+            Arg::with_name("lazy-fetch")
+                .long("lazy-fetch")
+                .help("List tags from --kernel-remote, and only fetch each tag just before it's analyzed.")
+                .requires("kernel-remote"),
+        )
+        .arg(
+            Arg::with_name("local-dir")
+                .long("local-dir")
+                .value_name("DIR")
+                .help("Analyze the kernel tree in the given directory, instead of released kernels.")
+                .takes_value(true)
+                .conflicts_with_all(&["local-tar", "kernel-git"]),
+        )
+        .arg(
+            Arg::with_name("local-tar")
+                .long("local-tar")
+                .value_name("PATH")
+                .help("Analyze the kernel archive at the given path, instead of released kernels.")
+                .takes_value(true)
+                .conflicts_with("kernel-git"),
+        )
+        .arg(
+            Arg::with_name("local-tag")
+                .long("local-tag")
+                .value_name("TAG")
+                .help("The tag to store statistics for --local-dir or --local-tar under [default: custom].")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show how statistics changed between two analyzed kernels.")
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .value_name("DIR")
+                        .help("Directory to read statistics from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("range")
+                        .value_name("FROM..TO")
+                        .help("The kernels to compare, like `v6.0..v6.6`.")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Analyze a single kernel, regardless of any existing statistics.")
+                .arg(
+                    Arg::with_name("version")
+                        .value_name("VERSION")
+                        .help("The kernel to analyze, like `v6.6`.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("stdout")
+                        .long("stdout")
+                        .help("Print the statistics as JSON to stdout, instead of storing them."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Download and analyze a single kernel without storing any statistics, and print how long each phase took.")
+                .setting(AppSettings::DisableVersion)
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .value_name("VERSION")
+                        .help("The kernel to benchmark, like `v6.6`.")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("download")
+                .about("Download the selected kernels into the cache, without analyzing anything.")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Download all kernels, not just the important ones."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Analyze only the kernels which are missing statistics, or whose statistics can't be read.")
+                .arg(
+                    Arg::with_name("migrate")
+                        .long("migrate")
+                        .help("Rewrite outputs written with an older schema in place, upgraded to the current one."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("summarize")
+                .about("Regenerate the summary from existing statistics, without analyzing kernels.")
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .value_name("DIR")
+                        .help("Directory to read statistics from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Path to write the summary to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Format to write the summary in, where jsonl writes one record per kernel and language on each line.")
+                        .takes_value(true)
+                        .possible_values(&["json", "jsonl"])
+                        .default_value("json"),
+                )
+                .arg(
+                    Arg::with_name("min-code")
+                        .long("min-code")
+                        .value_name("N")
+                        .help("Drop languages with fewer than N lines of code from the summary.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("other")
+                        .long("other")
+                        .help("Sum up the languages dropped by --min-code under `Other`, instead of discarding them.")
+                        .requires("min-code"),
+                )
+                .arg(
+                    Arg::with_name("migrate")
+                        .long("migrate")
+                        .help("Rewrite outputs written with an older schema in place, upgraded to the current one."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-cache")
+                .about("Test every archive in the cache, without downloading or analyzing anything.")
+                .arg(
+                    Arg::with_name("cache")
+                        .long("cache")
+                        .value_name("DIR")
+                        .help("The cache directory to check.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("update-index")
+                .about("Regenerate the list of kernel releases from the directory listings of the mirror, preserving existing annotations.")
+                .arg(
+                    Arg::with_name("mirror")
+                        .long("mirror")
+                        .value_name("URL")
+                        .help("Base URL of the mirror to scrape.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("kernels-file")
+                        .long("kernels-file")
+                        .value_name("PATH")
+                        .help("The list of releases to update, instead of the file given with --out, or the embedded list if that doesn't exist.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Path to write the updated list of releases to.")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-manifest")
+                .about("Verify that the statistics haven't changed since the given manifest was written.")
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .value_name("DIR")
+                        .help("Directory to read statistics from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .value_name("FILE")
+                        .help("Path to the manifest to verify against.")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("timeseries")
+                .about("Write the lines of code of each language for all kernels as CSV, one row per kernel.")
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .value_name("DIR")
+                        .help("Directory to read statistics from.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .help("Path to write the time series to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("min-code")
+                        .long("min-code")
+                        .value_name("N")
+                        .help("Drop languages with fewer than N lines of code from the summary.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("other")
+                        .long("other")
+                        .help("Sum up the languages dropped by --min-code under `Other`, instead of discarding them.")
+                        .requires("min-code"),
+                )
+                .arg(
+                    Arg::with_name("migrate")
+                        .long("migrate")
+                        .help("Rewrite outputs written with an older schema in place, upgraded to the current one."),
+                ),
+        )
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let matches = app().get_matches();
+    init_logger(matches.value_of("log-format").unwrap_or("pretty"))?;
+    let config = Config::load(matches.value_of("config").map(Path::new))?;
+
+    let mut renames = default_renames();
+    renames.extend(config.rename.clone());
+
+    let compression = compression(&matches)?;
+
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        let stats_dir = matches
+            .value_of("stats")
+            .map(PathBuf::from)
+            .or(config.stats)
+            .unwrap_or_else(|| PathBuf::from("stats"));
+
+        let range = matches
+            .value_of("range")
+            .ok_or_else(|| anyhow!("missing range"))?;
+        return diff(&stats_dir, range);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("check-cache") {
+        let cache_dir = matches
+            .value_of("cache")
+            .map(PathBuf::from)
+            .or(config.cache)
+            .unwrap_or_else(|| PathBuf::from("cache"));
+
+        return check_cache(&cache_dir);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("update-index") {
+        let mirror = match matches
+            .value_of("mirror")
+            .or_else(|| config.mirror.first().map(String::as_str))
+        {
+            Some(mirror) => kernels::parse_mirror(mirror)?,
+            None => kernels::URL_BASE.to_string(),
+        };
+
+        let out = matches
+            .value_of("out")
+            .ok_or_else(|| anyhow!("missing --out"))?;
+
+        let client = kernels::ClientOptions::default().build()?;
+        let kernels_file = matches.value_of("kernels-file").map(Path::new);
+        return update_index(&client, &mirror, kernels_file, Path::new(out)).await;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("verify-manifest") {
+        let stats_dir = matches
+            .value_of("stats")
+            .map(PathBuf::from)
+            .or(config.stats)
+            .unwrap_or_else(|| PathBuf::from("stats"));
+
+        let manifest = matches
+            .value_of("manifest")
+            .ok_or_else(|| anyhow!("missing --manifest"))?;
+        return verify_manifest(&stats_dir, Path::new(manifest));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("timeseries") {
+        let stats_dir = matches
+            .value_of("stats")
+            .map(PathBuf::from)
+            .or(config.stats)
+            .unwrap_or_else(|| PathBuf::from("stats"));
+
+        let out = matches
+            .value_of("out")
+            .ok_or_else(|| anyhow!("missing --out"))?;
+        let migrate = Some(compression).filter(|_| matches.is_present("migrate"));
+        let min_code = min_code(matches)?;
+        return timeseries(&stats_dir, Path::new(out), &renames, min_code, migrate);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("summarize") {
+        let stats_dir = matches
+            .value_of("stats")
+            .map(PathBuf::from)
+            .or(config.stats)
+            .unwrap_or_else(|| PathBuf::from("stats"));
+
+        let out = matches
+            .value_of("out")
+            .ok_or_else(|| anyhow!("missing --out"))?;
+        let migrate = Some(compression).filter(|_| matches.is_present("migrate"));
+        let min_code = min_code(matches)?;
+
+        return match matches.value_of("format") {
+            Some("jsonl") => {
+                summarize_jsonl(&stats_dir, Path::new(out), &renames, min_code, migrate)
+            }
+            _ => summarize(&stats_dir, Path::new(out), &renames, min_code, migrate),
+        };
+    }
+
+    // NB: analyzing a single kernel reuses the batch machinery, with the
+    // selection narrowed down to just that kernel.
+    let bench = matches.subcommand_matches("bench").is_some();
+    let single = matches
+        .subcommand_matches("analyze")
+        .or_else(|| matches.subcommand_matches("bench"));
+
+    let (selected, stdout) = match single {
+        Some(analyze) => {
+            for flag in BATCH_FLAGS {
+                if matches.is_present(flag) {
+                    return Err(anyhow!(
+                        "--{} can't be combined with {}",
+                        flag,
+                        matches.subcommand_name().unwrap_or("analyze")
+                    ));
+                }
+            }
+
+            let version = analyze
+                .value_of("version")
+                .ok_or_else(|| anyhow!("missing version"))?;
+            (
+                Some(vec![normalize_tag(version)]),
+                analyze.is_present("stdout"),
+            )
+        }
+        None => (None, false),
+    };
+
+    let analyze = selected.is_some();
+
+    let selected = match matches.value_of("jobs-from") {
+        Some(path) => Some(read_jobs(Path::new(path))?),
+        None => selected,
+    };
+
+    let kernel_git_dir = matches.value_of("kernel-git").map(Path::new);
+    let kernel_remote = matches.value_of("kernel-remote");
+    let branches = matches
+        .values_of("branch")
+        .map(|branches| branches.collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut git_config = Vec::new();
+
+    for config in matches.values_of("git-config").into_iter().flatten() {
+        match config.find('=') {
+            Some(n) if n > 0 => git_config.push(config.to_string()),
+            _ => {
+                return Err(anyhow!(
+                    "bad --git-config: {}: expected `key=value`",
+                    config
+                ))
+            }
+        }
+    }
+    let lazy_remote = kernel_remote.filter(|_| matches.is_present("lazy-fetch"));
+    let verify = matches.is_present("verify");
+    let revalidate = matches.is_present("revalidate");
+    let dry_run = matches.is_present("dry-run");
+    let on_error = match matches.value_of("on-error") {
+        Some("skip") => OnError::Skip,
+        Some("retry") => OnError::Retry,
+        Some(_) => OnError::Abort,
+        None if matches.is_present("keep-going") => OnError::Skip,
+        None => OnError::Abort,
+    };
+
+    let keep_going = on_error != OnError::Abort;
+
+    let retries = match (on_error, matches.value_of("retries")) {
+        (OnError::Retry, Some(n)) => {
+            str::parse(n).map_err(|e| anyhow!("failed to parse retries: {}", e))?
+        }
+        (OnError::Retry, None) => DEFAULT_RETRIES,
+        (_, Some(_)) => return Err(anyhow!("--retries requires --on-error retry")),
+        (_, None) => 0,
+    };
+    let force = matches.is_present("force") || analyze;
+    let outdated = matches.is_present("outdated");
+    let repair = matches.subcommand_matches("repair").is_some();
+    let migrate = Some(compression).filter(
+        |_| matches!(matches.subcommand_matches("repair"), Some(m) if m.is_present("migrate")),
+    );
+    let keep_work = matches.is_present("keep-work");
+    let incremental = matches.is_present("incremental");
+    let churn = matches.is_present("churn");
+    let validate_lineage = matches.is_present("validate-lineage");
+    let dedup = matches.is_present("dedup");
+    let discard_archives = matches.is_present("discard-archives");
+    let discard_all = matches.is_present("discard-all");
+    let download = matches.subcommand_matches("download");
+    let all = matches.is_present("all")
+        || selected.is_some()
+        || matches!(download, Some(m) if m.is_present("all"));
+
+    let cache_dir = matches
+        .value_of("cache")
+        .map(PathBuf::from)
+        .or(config.cache)
+        .unwrap_or_else(|| PathBuf::from("cache"));
+    let cache_dir = cache_dir.as_path();
+
+    let work_dir = matches
+        .value_of("work")
+        .map(PathBuf::from)
+        .or(config.work)
+        .unwrap_or_else(|| PathBuf::from("work"));
+
+    let memory_dir = match matches.value_of("work-in-memory") {
+        Some(size) => memory_work_dir(
+            str::parse::<u64>(size)
+                .map_err(|e| anyhow!("failed to parse work in memory size: {}", e))?,
+        )?,
+        None => None,
+    };
+
+    // NB: benchmarks download into an empty cache, so that the download is
+    // always timed.
+    let bench_cache = if bench {
+        Some(work_dir.join(format!("bench-cache-{}", process::id())))
+    } else {
+        None
+    };
+
+    let _bench_guard = bench_cache.clone().map(WorkDir::new);
+
+    let cache_dir = bench_cache.as_deref().unwrap_or(cache_dir);
+
+    // NB: removes the directory in memory once all kernels are analyzed.
+    let _memory_guard = memory_dir.clone().map(WorkDir::new);
+
+    let work_dir = memory_dir.as_deref().unwrap_or(&work_dir);
+
+    let (stats_dir, layout) = match matches.value_of("output-dir") {
+        Some(dir) => (PathBuf::from(dir), Layout::Nested),
+        None => {
+            let stats_dir = matches
+                .value_of("stats")
+                .map(PathBuf::from)
+                .or(config.stats)
+                .unwrap_or_else(|| PathBuf::from("stats"));
+
+            (stats_dir, Layout::Flat)
+        }
+    };
+    let stats_dir = stats_dir.as_path();
+
+    let format = Format::parse(matches.value_of("format").unwrap_or("json"))?;
+    let summary_path = matches.value_of("summary").map(Path::new);
+    let sqlite_path = matches.value_of("sqlite").map(Path::new);
+
+    if summary_path.is_some() && !format.is_json() {
+        return Err(anyhow!("--summary requires outputs in the json format"));
+    }
+
+    if outdated && (!format.is_json() || sqlite_path.is_some()) {
+        return Err(anyhow!("--outdated requires outputs in the json format"));
+    }
+
+    if repair && (!format.is_json() || sqlite_path.is_some()) {
+        return Err(anyhow!("repair requires outputs in the json format"));
+    }
+
+    if summary_path.is_some() && sqlite_path.is_some() {
+        return Err(anyhow!("--summary can't be combined with --sqlite"));
+    }
+
+    let prometheus_path = matches.value_of("prometheus").map(Path::new);
+    let html_path = matches.value_of("html").map(Path::new);
+    let dump_urls_path = matches.value_of("dump-urls").map(Path::new);
+
+    if prometheus_path.is_some() && !format.is_json() {
+        return Err(anyhow!("--prometheus requires outputs in the json format"));
+    }
+
+    if prometheus_path.is_some() && sqlite_path.is_some() {
+        return Err(anyhow!("--prometheus can't be combined with --sqlite"));
+    }
+
+    let manifest_path = matches.value_of("manifest").map(Path::new);
+
+    if manifest_path.is_some() && (!format.is_json() || sqlite_path.is_some()) {
+        return Err(anyhow!("--manifest requires outputs in the json format"));
+    }
+
+    let parallelism = match matches.value_of("parallelism") {
+        Some(p) => Some(
+            str::parse::<usize>(p).map_err(|e| anyhow!("failed to parse parallelism: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let download_parallelism = match matches.value_of("download-parallelism") {
+        Some(p) => {
+            str::parse(p).map_err(|e| anyhow!("failed to parse download parallelism: {}", e))?
+        }
+        None => parallelism.or(config.parallelism).unwrap_or(2),
+    };
+
+    let analysis_parallelism = match matches.value_of("analysis-parallelism") {
+        Some(p) => {
+            str::parse(p).map_err(|e| anyhow!("failed to parse analysis parallelism: {}", e))?
+        }
+        None => parallelism.unwrap_or(1),
+    };
+
+    if download_parallelism == 0 || analysis_parallelism == 0 {
+        return Err(anyhow!("parallelism must be at least 1"));
+    }
+
+    if incremental && analysis_parallelism > 1 {
+        return Err(anyhow!(
+            "--incremental analyzes tags one at a time in a single worktree, and can't be combined with parallel analysis"
+        ));
+    }
+
+    let max_bandwidth = match matches.value_of("max-bandwidth") {
+        Some(b) => Some(
+            str::parse::<u64>(b)
+                .ok()
+                .filter(|b| *b > 0)
+                .ok_or_else(|| anyhow!("bad --max-bandwidth: {}: expected bytes per second", b))?,
+        ),
+        None => None,
+    };
+
+    let format = match (format, matches.is_present("no-compress")) {
+        (Format::Json, true) => Format::PlainJson,
+        (_, true) => return Err(anyhow!("--no-compress requires outputs in the json format")),
+        (format, false) => format,
+    };
+
+    let min_free_space = match matches.value_of("min-free-space") {
+        Some(s) => Some(
+            str::parse::<u64>(s).map_err(|e| anyhow!("failed to parse min free space: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let cache_max_size = match matches.value_of("cache-max-size") {
+        Some(s) => Some(
+            str::parse::<u64>(s).map_err(|e| anyhow!("failed to parse cache max size: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let limit = match matches.value_of("limit") {
+        Some(l) => {
+            Some(str::parse::<usize>(l).map_err(|e| anyhow!("failed to parse limit: {}", e))?)
+        }
+        None => None,
+    };
+
+    let since = match matches.value_of("since") {
+        Some(since) => {
+            Some(Version::parse(since).ok_or_else(|| anyhow!("bad --since version: {}", since))?)
+        }
+        None => None,
+    };
+
+    let until = match matches.value_of("until") {
+        Some(until) => {
+            Some(Version::parse(until).ok_or_else(|| anyhow!("bad --until version: {}", until))?)
+        }
+        None => None,
+    };
+
+    // Test if the given version is in the range selected by `--since` and
+    // `--until`.
+    let in_range = |version: &str| {
+        if since.is_none() && until.is_none() {
+            return true;
+        }
+
+        let version = match Version::parse(version) {
+            Some(version) => version,
+            None => {
+                info!("skipping unrecognized version: {}", version);
+                return false;
+            }
+        };
+
+        since
+            .as_ref()
+            .map(|since| version >= *since)
+            .unwrap_or(true)
+            && until
+                .as_ref()
+                .map(|until| version <= *until)
+                .unwrap_or(true)
+    };
+
+    let include_rc = matches.is_present("include-rc");
+    let rc_only = matches.is_present("rc-only");
+
+    // Test if the given version should be processed, depending on if it's a
+    // release candidate. By default release candidates are skipped, unless
+    // they're explicitly selected.
+    let rc_filter = |version: &str| {
+        let rc = version.trim_end_matches(char::is_numeric).ends_with("-rc");
+
+        if rc_only {
+            rc
+        } else {
+            include_rc || !rc
+        }
+    };
+
+    let is_selected = |tag: &str| {
+        selected
+            .as_ref()
+            .map(|selected| selected.iter().any(|s| s == tag))
+            .unwrap_or(false)
+    };
+
+    let tokei_timeout = match matches.value_of("tokei-timeout") {
+        Some(t) => str::parse(t).map_err(|e| anyhow!("failed to parse tokei timeout: {}", e))?,
+        None => tokei::DEFAULT_TIMEOUT.as_secs(),
+    };
+
+    let top_files = match matches.value_of("top-files") {
+        Some(n) => {
+            Some(str::parse::<usize>(n).map_err(|e| anyhow!("failed to parse top files: {}", e))?)
+        }
+        None => None,
+    };
+
+    let mut tokei = Tokei {
+        bin: PathBuf::from(matches.value_of("tokei-bin").unwrap_or("tokei")),
+        excludes: matches
+            .values_of("exclude")
+            .map(|excludes| excludes.map(String::from).collect())
+            .unwrap_or(config.exclude),
+        timeout: Duration::from_secs(tokei_timeout),
+        args: matches
+            .values_of("tokei-arg")
+            .map(|args| args.map(String::from).collect())
+            .unwrap_or_default(),
+        version: None,
+        process_group: true,
+        top_files,
+        languages: matches
+            .values_of("only-language")
+            .map(|languages| languages.map(String::from).collect())
+            .unwrap_or_default(),
+        paths: matches
+            .values_of("path")
+            .into_iter()
+            .flatten()
+            .map(normalize_path)
+            .collect::<Result<_>>()?,
+        extensions: matches
+            .value_of("extensions")
+            .map(|extensions| {
+                extensions
+                    .split(',')
+                    .map(|e| e.trim().trim_start_matches('.'))
+                    .filter(|e| !e.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        renames,
+        keep_raw: matches.is_present("keep-raw-tokei"),
+        min_code: min_code(&matches)?,
+        follow_symlinks: matches.is_present("follow-symlinks"),
+        // NB: a kernel tree always has code, but the directories selected with
+        // --path might not.
+        require_code: !matches.is_present("path"),
+        reports: matches
+            .values_of("subsystem-report")
+            .into_iter()
+            .flatten()
+            .map(|name| {
+                stats::Report::find(name).ok_or_else(|| {
+                    let names = stats::REPORTS.iter().map(|r| r.name).collect::<Vec<_>>();
+                    anyhow!(
+                        "unknown subsystem report: {}: expected one of: {}",
+                        name,
+                        names.join(", ")
+                    )
+                })
+            })
+            .collect::<Result<_>>()?,
+    };
+
+    for arg in &tokei.args {
+        Tokei::validate_arg(arg)?;
+    }
+
+    // NB: checked before anything is fetched, so that a missing tokei is
+    // reported before spending time on downloads.
+    if !verify && !dry_run && download.is_none() {
+        tokei.check()?;
+    }
+
+    let mut mirrors = match matches.values_of("mirror") {
+        Some(mirrors) => mirrors
+            .map(kernels::parse_mirror)
+            .collect::<Result<Vec<_>>>()?,
+        None if !config.mirror.is_empty() => config
+            .mirror
+            .iter()
+            .map(|m| kernels::parse_mirror(m))
+            .collect::<Result<Vec<_>>>()?,
+        // NB: defaults to the sources of the kernels file, which is read
+        // below.
+        None => Vec::new(),
+    };
+
+    let connect_timeout = match matches.value_of("connect-timeout") {
+        Some(t) => {
+            Some(Duration::from_secs(str::parse(t).map_err(|e| {
+                anyhow!("failed to parse connect timeout: {}", e)
+            })?))
+        }
+        None => None,
+    };
+
+    let timeout = match matches.value_of("timeout") {
+        Some(t) => Some(Duration::from_secs(
+            str::parse(t).map_err(|e| anyhow!("failed to parse timeout: {}", e))?,
+        )),
+        None => None,
+    };
+
+    let client_options = kernels::ClientOptions {
+        proxy: matches.value_of("proxy").map(String::from),
+        connect_timeout,
+        timeout,
+    };
+
+    let post_url = match matches.value_of("post-url") {
+        Some(url) => {
+            reqwest::Url::parse(url).map_err(|e| anyhow!("bad --post-url: {}: {}", url, e))?;
+            Some(url)
+        }
+        None => None,
+    };
+
+    let local_dir = matches.value_of("local-dir").map(Path::new);
+    let local_tar = matches.value_of("local-tar").map(Path::new);
+
+    let mut local = match (local_dir, local_tar) {
+        (Some(dir), _) if !dir.is_dir() => {
+            return Err(anyhow!("not a directory: {}", dir.display()));
+        }
+        (_, Some(tar)) if !tar.is_file() => {
+            return Err(anyhow!("not a file: {}", tar.display()));
+        }
+        (Some(path), _) | (_, Some(path)) => {
+            let tag = matches.value_of("local-tag").unwrap_or("custom");
+
+            if tag.is_empty() || tag.contains(&['/', '\\'][..]) {
+                return Err(anyhow!("bad --local-tag: {}", tag));
+            }
+
+            Some((tag.to_string(), path))
+        }
+        (None, None) => {
+            if matches.is_present("local-tag") {
+                return Err(anyhow!("--local-tag requires --local-dir or --local-tar"));
+            }
+
+            None
+        }
+    };
+
+    let mut a = env::args();
+    a.next();
+
+    let Kernels {
+        sources,
+        mut releases,
+    } = match matches.value_of("kernels-file") {
+        Some(path) => kernels::kernels_from_file(Path::new(path))?,
+        None => kernels::kernels()?,
+    };
 
-    if !out.status.success() {
-        let out = str::from_utf8(&out.stderr)?;
-        return Err(anyhow!("git error: {}", out).into());
+    if mirrors.is_empty() {
+        mirrors = sources.iter().map(|s| s.url.clone()).collect();
     }
 
-    let stdout = str::from_utf8(&out.stdout)?;
-    Ok(serde_json::from_str(&stdout)?)
-}
+    if mirrors.is_empty() {
+        mirrors.push(kernels::URL_BASE.to_string());
+    }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct Stat {
-    blanks: u64,
-    code: u64,
-    comments: u64,
-    lines: u64,
-    name: PathBuf,
-}
+    let skipped_tags = releases
+        .iter()
+        .filter(|r| r.skip)
+        .map(|r| r.tag())
+        .collect::<HashSet<_>>();
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct LanguageStats {
-    blanks: u64,
-    code: u64,
-    comments: u64,
-    lines: u64,
-    stats: Vec<Stat>,
-}
+    if !all {
+        releases = releases.into_iter().filter(|v| v.important).collect();
+    }
 
-impl ops::AddAssign for LanguageStats {
-    fn add_assign(&mut self, other: LanguageStats) {
-        self.blanks += other.blanks;
-        self.code += other.code;
-        self.comments += other.comments;
-        self.lines += other.lines;
-        self.stats.extend(other.stats);
+    releases.retain(|v| {
+        in_range(v.version()) && (rc_filter(v.version()) || is_selected(&format!("v{}", v)))
+    });
+
+    // NB: a local kernel replaces the released kernels.
+    if local.is_some() {
+        releases.clear();
     }
-}
 
-/// The output of analyzing a single kernel.
-#[derive(Debug, Serialize)]
-pub struct Output {
-    /// The tag that we build for.
-    tag: String,
-    /// Statistics for all languages.
-    all: HashMap<String, LanguageStats>,
-}
+    if let Some(selected) = &selected {
+        releases.retain(|v| selected.contains(&format!("v{}", v)));
+    }
 
-impl Output {
-    /// Construct a new kernel output.
-    pub fn new(tag: String) -> Output {
-        Output {
-            tag,
-            all: Default::default(),
-        }
+    if download.is_some() {
+        return download_only(
+            &client_options.build()?,
+            cache_dir,
+            &releases,
+            &mirrors,
+            &sources,
+            verify,
+            revalidate,
+            download_parallelism,
+            keep_going,
+            retries,
+            dedup,
+            max_bandwidth,
+            min_free_space,
+        )
+        .await;
     }
-}
 
-/// A kernel to build, the path it's
-#[derive(Debug, Clone)]
-pub enum Kernel<'a> {
-    /// A kernel tar.gz that needs to be unpacked.
-    Cached {
-        /// The version of the cached kernel, in `v{major}.{minor}` format.
-        version: String,
-        /// Path to the cached kernel.
-        path: &'a Path,
-    },
-    /// A git directory tag.
-    Git {
-        /// The tag of the kernel.
-        tag: String,
-        /// The git handle for the kernel.
-        git: Git<'a>,
-    },
-}
+    let mut git = None;
+    let mut git_tags = Vec::new();
+    let mut branch_heads = HashMap::new();
+
+    if let Some(kernel_git_dir) = kernel_git_dir {
+        let g = Git::new(&kernel_git_dir).with_config(&git_config);
 
-impl<'a> Kernel<'a> {
-    /// Get the version of the kernel.
-    pub fn version(&self) -> &str {
-        match *self {
-            Kernel::Cached { ref version, .. } => version.as_str(),
-            Kernel::Git { ref tag, .. } => tag.as_str(),
+        if !kernel_git_dir.is_dir() {
+            let remote = match kernel_remote {
+                Some(remote) if !dry_run => remote,
+                _ => return Err(anyhow!("missing kernel directory")),
+            };
+
+            g.init()?;
+
+            if lazy_remote.is_none() {
+                info!("cloning {} into: {}", remote, kernel_git_dir.display());
+                g.fetch(remote)?;
+            }
         }
-    }
 
-    /// Analyze the given kernel.
-    pub fn analyze(self, work_dir: &Path) -> Result<Output> {
-        match self {
-            Kernel::Cached { version, path } => {
-                use flate2::read::GzDecoder;
-                use tar::Archive;
+        // NB: with lazy fetching, tags are fetched one at a time just before
+        // they're analyzed.
+        let tags = match lazy_remote {
+            Some(remote) => sort_semver(g.remote_tags(remote)?),
+            None => g.tags_sorted_semver()?,
+        };
+
+        // NB: commit counts and churn need the history between tags, which a
+        // shallow clone like the one bootstrapped from `--kernel-remote`
+        // doesn't have.
+        let shallow = lazy_remote.is_some() || g.is_shallow()?;
 
-                let work_dir = work_dir.join(format!("linux-{}", version));
+        if shallow && churn {
+            return Err(anyhow!(
+                "--churn needs the full history, but the repository is shallow: {}",
+                kernel_git_dir.display()
+            ));
+        }
 
-                if !work_dir.is_dir() {
-                    let f = fs::File::open(path).map_err(|e| {
-                        anyhow!("failed to open cached archive: {}: {}", path.display(), e)
-                    })?;
-                    let mut a = Archive::new(GzDecoder::new(f));
+        let mut previous = None;
 
-                    a.unpack(&work_dir)
-                        .with_context(|| anyhow!("failed to unpack archive: {}", path.display()))?;
+        for tag in tags {
+            match tag.as_str() {
+                tag if skipped_tags.contains(tag) => {
+                    info!("skipping tag: {}", tag);
+                    continue;
+                }
+                tag if tag.ends_with("-tree") => continue,
+                tag if !rc_filter(tag) && !is_selected(tag) => {
+                    info!("skipping: {}", tag);
+                    continue;
                 }
+                _ => {}
+            }
 
-                let e = fs::read_dir(&work_dir)
-                    .with_context(|| anyhow!("failed to read directory: {}", work_dir.display()))?
-                    .next()
-                    .ok_or_else(|| anyhow!("no sub-directory in: {}", work_dir.display()))?;
+            // NB: the previous release is tracked before filtering by range,
+            // so that the first tag in range still knows its predecessor. A
+            // release candidate which is only processed because it's selected
+            // never becomes the predecessor of another tag.
+            let prev = if rc_filter(&tag) {
+                previous.replace(tag.clone())
+            } else {
+                previous.clone()
+            };
 
-                let output_dir = e
-                    .with_context(|| anyhow!("no entry: {}", work_dir.display()))?
-                    .path();
+            if !in_range(&tag) {
+                continue;
+            }
 
-                if !output_dir.is_dir() {
-                    return Err(anyhow!("missing linux directory: {}", output_dir.display()).into());
+            if let Some(selected) = &selected {
+                if !selected.contains(&tag) {
+                    continue;
                 }
+            }
 
-                let mut output = Output::new(version.to_string());
-                output.all = tokei(&output_dir).context("running tokei")?;
+            git_tags.push((tag, prev.filter(|_| !shallow)));
+        }
 
-                fs::remove_dir_all(&work_dir)
-                    .map_err(|e| anyhow!("failed to remove dir: {}: {}", work_dir.display(), e))?;
-                Ok(output)
+        // NB: commit counts and churn are relative to the previous tag, which
+        // is only meaningful if it's part of the history of the tag.
+        if validate_lineage {
+            for (tag, prev) in &git_tags {
+                if let Some(prev) = prev {
+                    if !g.is_ancestor(prev, tag)? {
+                        warn!(
+                            "lineage: {} is not an ancestor of {}, commit counts and churn relative to it may be misleading",
+                            prev, tag
+                        );
+                    }
+                }
             }
-            Kernel::Git { tag, git } => {
-                info!("building statistics for release: {}", tag);
-                git.checkout_hard(&tag)?;
+        }
 
-                let mut output = Output::new(tag);
-                output.all = tokei(git.repo).context("running tokei")?;
-                Ok(output)
+        // NB: the head of a maintenance branch is labeled with its commit,
+        // since the branch moves over time.
+        for pattern in &branches {
+            let remote = kernel_remote.unwrap_or(".");
+
+            for (branch, commit) in g.remote_branches(remote, pattern)? {
+                let tag = format!(
+                    "{}@{}",
+                    branch.replace('/', "-"),
+                    &commit[..commit.len().min(12)]
+                );
+
+                if let Some(selected) = &selected {
+                    if !selected.contains(&tag) {
+                        continue;
+                    }
+                }
+
+                branch_heads.insert(tag.clone(), (branch, commit));
+                git_tags.push((tag, None));
             }
         }
+
+        git = Some(g);
     }
-}
 
-fn app() -> App<'static, 'static> {
-    App::new("kernelstats")
-        .version("0.0.1")
-        .author("John-John Tedro <udoprog@tedro.se>")
-        .about("Calculates statistics across kernel releases.")
-        .arg(
-            Arg::with_name("verify")
-                .long("verify")
-                .help("Verify that all kernels are available."),
-        )
-        .arg(
-            Arg::with_name("all")
-                .long("all")
-                .help("Build all kernel versions, not just important."),
-        )
-        .arg(
-            Arg::with_name("cache")
-                .long("cache")
-                .value_name("DIR")
-                .help("Sets the path to the cache directory.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("work")
-                .long("work")
-                .value_name("DIR")
-                .help("Sets the path to the work directory.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("stats")
-                .long("stats")
-                .value_name("DIR")
-                .help("Directory to store statistics in.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("parallelism")
-                .long("parallelism")
-                .short("p")
-                .value_name("<count>")
-                .help("How many downloads to perform in parallel.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("kernel-git")
-                .long("kernel-git")
-                .value_name("DIR")
-                .help("Sets the path to a kernel git directory.")
-                .takes_value(true),
-        )
-}
+    if let Some(selected) = &selected {
+        let tags = git_tags
+            .iter()
+            .map(|(tag, _)| tag.as_str())
+            .collect::<HashSet<_>>();
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    pretty_env_logger::init();
+        let missing = selected
+            .iter()
+            .filter(|s| {
+                !tags.contains(s.as_str()) && !releases.iter().any(|v| format!("v{}", v) == **s)
+            })
+            .map(String::as_str)
+            .collect::<Vec<_>>();
 
-    let matches = app().get_matches();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "no such kernel: {}: it's neither in kernels.yaml nor a tag in --kernel-git",
+                missing.join(", ")
+            ));
+        }
 
-    let kernel_git_dir = matches.value_of("kernel-git").map(Path::new);
-    let verify = matches.is_present("verify");
-    let all = matches.is_present("all");
+        // NB: prefer git, since it doesn't need a download.
+        releases.retain(|v| !tags.contains(format!("v{}", v).as_str()));
+    }
 
-    let cache_dir = matches
-        .value_of("cache")
-        .map(Path::new)
-        .unwrap_or_else(|| Path::new("cache"));
+    // NB: when repairing, only kernels without valid statistics are kept, so
+    // that nothing else is downloaded or analyzed.
+    if repair {
+        let total = releases.len() + git_tags.len() + local.iter().count();
+        let missing = |version: &str| match existing_output(stats_dir, layout, version, format) {
+            Some(p) => needs_repair(&p, migrate),
+            None => true,
+        };
 
-    let work_dir = matches
-        .value_of("work")
-        .map(Path::new)
-        .unwrap_or_else(|| Path::new("work"));
+        releases.retain(|r| missing(&format!("v{}", r)));
+        git_tags.retain(|(tag, _)| missing(tag));
+        local = local.filter(|(tag, _)| missing(tag));
 
-    let stats_dir = matches
-        .value_of("stats")
-        .map(Path::new)
-        .unwrap_or_else(|| Path::new("stats"));
+        let remaining = releases.len() + git_tags.len() + local.iter().count();
+        info!("{} of {} kernel(s) need repair", remaining, total);
+    }
 
-    let parallelism = match matches.value_of("parallelism") {
-        Some(p) => str::parse(p).map_err(|e| anyhow!("failed to parse parallelism: {}", e))?,
-        None => 2,
-    };
+    // NB: the limit is applied in queue order, cached kernels first, before
+    // anything is downloaded.
+    if let Some(limit) = limit {
+        let total = releases.len() + git_tags.len();
+        releases.truncate(limit);
+        git_tags.truncate(limit - releases.len());
 
-    use std::io::Write;
+        if total > limit {
+            info!(
+                "dropped {} kernel(s) due to --limit {}",
+                total - limit,
+                limit
+            );
+        }
+    }
 
-    let mut a = env::args();
-    a.next();
+    if let Some(dump_urls_path) = dump_urls_path {
+        info!("writing urls: {}", dump_urls_path.display());
+        dump_urls(dump_urls_path, &releases, &mirrors, &sources)?;
+    }
+
+    if dry_run {
+        let database = match sqlite_path {
+            Some(sqlite_path) if sqlite_path.is_file() => Some(Database::open(sqlite_path)?),
+            _ => None,
+        };
+
+        let mut plan = Vec::new();
+
+        for release in &releases {
+            let archive = release.cache_path(cache_dir);
+
+            let source = if archive.is_file() {
+                format!("use {}", archive.display())
+            } else {
+                format!(
+                    "download {} -> {}",
+                    release.archive_url(release.mirrors(&mirrors, &sources)[0])?,
+                    archive.display()
+                )
+            };
+
+            plan.push((format!("v{}", release), source));
+        }
+
+        for (tag, _) in &git_tags {
+            let reference = match branch_heads.get(tag) {
+                Some((_, commit)) => commit,
+                None => tag,
+            };
+
+            plan.push((tag.clone(), format!("checkout {}", reference)));
+        }
+
+        if let Some((tag, path)) = &local {
+            plan.push((tag.clone(), format!("use {}", path.display())));
+        }
+
+        for (version, source) in plan {
+            let (target, exists) = match sqlite_path {
+                Some(sqlite_path) => {
+                    let exists = match &database {
+                        Some(database) => database.contains(&version)?,
+                        None => false,
+                    };
+
+                    (sqlite_path.display().to_string(), exists)
+                }
+                None => match existing_output(stats_dir, layout, &version, format) {
+                    Some(p) => (p.display().to_string(), true),
+                    None => {
+                        let p = output_path(stats_dir, layout, &version, format);
+                        (p.display().to_string(), false)
+                    }
+                },
+            };
+
+            let action = match (exists, force || repair) {
+                (true, true) => "overwrite",
+                (true, false) => "skip",
+                (false, _) => "write",
+            };
+            println!("{}: {}, {} {}", version, source, action, target);
+        }
+
+        return Ok(());
+    }
 
     if !cache_dir.is_dir() {
         fs::create_dir_all(cache_dir).map_err(|e| {
@@ -255,50 +2586,115 @@ async fn main() -> Result<()> {
         })?;
     }
 
-    let Kernels { mut releases } = kernels::kernels()?;
+    let mut queue = Vec::new();
 
-    if !all {
-        releases = releases.into_iter().filter(|v| v.important).collect();
+    // NB: archives which were cached before this run are only discarded with
+    // `--discard-all`.
+    let preexisting = releases
+        .iter()
+        .map(|r| r.cache_path(cache_dir))
+        .filter(|p| p.is_file())
+        .collect::<HashSet<_>>();
+
+    info!("downloading old kernels to: {}", cache_dir.display());
+    let client = client_options.build()?;
+
+    let downloaded = kernels::download_old_kernels(
+        &client,
+        cache_dir,
+        &releases,
+        &mirrors,
+        &sources,
+        verify,
+        revalidate,
+        download_parallelism,
+        keep_going,
+        retries,
+        dedup,
+        max_bandwidth,
+        min_free_space,
+    )
+    .await?;
+
+    let mut failures = Vec::new();
+
+    for (release, e) in downloaded.failed {
+        failures.push((format!("v{}", release), e));
     }
 
-    let mut queue = Vec::new();
+    let mut discard = HashMap::new();
 
-    info!("downloading old kernels to: {}", cache_dir.display());
-    let cached = kernels::download_old_kernels(cache_dir, &releases, verify, parallelism).await?;
+    for kernel in &downloaded.cached {
+        let cache_path = kernel.version.cache_path(cache_dir);
+
+        // NB: objects of the content-addressed store might be shared with
+        // other versions, so only the link to it is discarded.
+        if discard_archives && (discard_all || !preexisting.contains(&cache_path)) {
+            discard.insert(format!("v{}", kernel.version), cache_path);
+        }
 
-    for kernel in &cached {
         queue.push(Kernel::Cached {
             version: format!("v{}", kernel.version),
             path: &kernel.path,
+            released: kernel.version.date.clone(),
+            mirror: kernel.mirror.as_deref(),
+            source: kernel.source.as_deref(),
+            download_time: kernel.download_time,
+            sha256: kernel.sha256.as_deref(),
         });
 
         info!("downloaded: {}", kernel.path.display());
     }
 
-    if let Some(kernel_git_dir) = kernel_git_dir {
-        if !kernel_git_dir.is_dir() {
-            return Err(anyhow!("missing kernel directory"));
-        }
+    // NB: every archive downloaded or found in this run is about to be
+    // analyzed, so none of them can be evicted.
+    if let Some(cache_max_size) = cache_max_size {
+        let keep = downloaded
+            .cached
+            .iter()
+            .flat_map(|k| vec![k.path.clone(), k.version.cache_path(cache_dir)])
+            .collect::<HashSet<_>>();
 
-        let git = Git::new(&kernel_git_dir);
+        kernels::evict_cache(cache_dir, cache_max_size, &keep)?;
+    }
 
-        for tag in git.tags()? {
-            match tag.as_str() {
-                // NB: not a commit
-                "v2.6.11" => continue,
-                tag if tag.ends_with("-tree") => continue,
-                // NB: skip release candidates.
-                tag if tag.trim_end_matches(char::is_numeric).ends_with("-rc") => {
-                    info!("skipping release candidate: {}", tag);
-                    continue;
+    if let Some(git) = git {
+        for (tag, prev) in git_tags {
+            if let Some((branch, commit)) = branch_heads.remove(&tag) {
+                if let Some(remote) = kernel_remote {
+                    info!("fetching {} from: {}", branch, remote);
+                    git.fetch_branch(remote, &branch)?;
                 }
-                _ => {}
+
+                queue.push(Kernel::Git {
+                    tag,
+                    reference: commit,
+                    prev: None,
+                    git,
+                    remote: None,
+                    incremental,
+                    churn,
+                });
+
+                continue;
             }
 
-            queue.push(Kernel::Git { tag, git });
+            queue.push(Kernel::Git {
+                reference: tag.clone(),
+                tag,
+                prev,
+                git,
+                remote: lazy_remote,
+                incremental,
+                churn,
+            });
         }
     }
 
+    if let Some((tag, path)) = local {
+        queue.push(Kernel::Local { tag, path });
+    }
+
     if verify {
         for q in queue {
             info!("verified: {:?}", q);
@@ -307,7 +2703,54 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    if !stats_dir.is_dir() {
+    if bench {
+        let q = queue
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no kernel to benchmark"))?;
+
+        let version = q.version().to_string();
+        let output = q.analyze(work_dir, keep_work, &tokei)?;
+        let timings = output.meta.timings;
+        let total = timings.download + timings.unpack + timings.tokei;
+
+        println!("{}:", version);
+        println!("  download: {:>8.2}s", timings.download.as_secs_f64());
+        println!("  unpack:   {:>8.2}s", timings.unpack.as_secs_f64());
+        println!("  tokei:    {:>8.2}s", timings.tokei.as_secs_f64());
+        println!("  total:    {:>8.2}s", total.as_secs_f64());
+        return Ok(());
+    }
+
+    if stdout {
+        use std::io::Write as _;
+
+        let q = queue
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no kernel to analyze"))?;
+
+        let output = q.analyze(work_dir, keep_work, &tokei)?;
+
+        if let Some(html_path) = html_path {
+            info!("writing html: {}", html_path.display());
+            write_html(html_path, &output)?;
+        }
+
+        let out = std::io::stdout();
+        let mut out = out.lock();
+        serde_json::to_writer_pretty(&mut out, &output)
+            .map_err(|e| anyhow!("failed to serialize: {}", e))?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    let mut database = match sqlite_path {
+        Some(sqlite_path) => Some(Database::open(sqlite_path)?),
+        None => None,
+    };
+
+    if database.is_none() && !stats_dir.is_dir() {
         fs::create_dir_all(stats_dir).map_err(|e| {
             anyhow!(
                 "failed to create stats directory: {}: {}",
@@ -317,32 +2760,273 @@ async fn main() -> Result<()> {
         })?;
     }
 
+    let mut outputs = Vec::new();
+    let mut pending = Vec::new();
+    let mut seen = HashSet::new();
+
+    // NB: which kernels need to be analyzed is decided up front, so that
+    // only the analysis itself runs in parallel.
     for q in queue {
-        use flate2::write::GzEncoder;
-        use flate2::Compression;
+        let version = q.version().to_string();
+
+        // NB: kernels with the same version would be analyzed in the same
+        // work directory, and stored in the same place.
+        if !seen.insert(version.clone()) {
+            warn!("skipping duplicate kernel: {:?}", q);
+            continue;
+        }
 
-        info!("process: {:?}", q);
+        let needed = (|| {
+            if let Some(database) = &database {
+                return Ok(force || !database.contains(&version)?);
+            }
 
-        let p = stats_dir.join(format!("linux-{}.json.gz", q.version()));
+            let p = match existing_output(stats_dir, layout, &version, format) {
+                Some(p) => p,
+                None => return Ok(true),
+            };
 
-        if p.is_file() {
-            continue;
+            if force || repair || (outdated && is_outdated(&p, &tokei)?) {
+                return Ok(true);
+            }
+
+            outputs.push(p);
+            Ok::<_, anyhow::Error>(false)
+        })();
+
+        match needed.map_err(Error::Io) {
+            Ok(true) => pending.push(q),
+            Ok(false) => (),
+            Err(e) if keep_going => {
+                warn!("failed to process: {}: {}", version, e);
+                failures.push((version, e));
+            }
+            Err(e) => return Err(e.into()),
         }
+    }
 
-        let output = q.analyze(&work_dir)?;
+    let interrupted = interrupt_handler();
+    let runtime = tokio::runtime::Handle::current();
+    let mut analyzed = 0;
+    let mut timings = Timings::default();
+    let mut latest = None;
 
-        let o = fs::File::create(&p)
-            .map_err(|e| anyhow!("failed to create output file: {}: {}", p.display(), e))?;
+    // NB: outputs are stored on this thread as they're built, since the
+    // database can't be shared with the analysis threads.
+    let store = |version: String, output: Result<Output, Error>| {
+        let result = output.and_then(|output| {
+            let stored = (|| {
+                if let Some(database) = database.as_mut() {
+                    database.remove(&output.tag)?;
+                    database.insert(&output)?;
+                } else {
+                    let p = output_path(stats_dir, layout, &version, format);
 
-        let mut o = GzEncoder::new(o, Compression::default());
+                    if let Some(parent) = p.parent() {
+                        fs::create_dir_all(parent).map_err(|e| {
+                            anyhow!("failed to create directory: {}: {}", parent.display(), e)
+                        })?;
+                    }
 
-        serde_json::to_writer(&mut o, &output)
-            .map_err(|e| anyhow!("failed to serialize: {}", e))?;
-        writeln!(o, "")?;
+                    match format {
+                        Format::Json => write_json(&p, &output, compression)?,
+                        Format::PlainJson => write_plain_json(&p, &output)?,
+                        Format::Csv => write_csv(&p, &output)?,
+                    }
+
+                    // NB: remove the output in the other json variant, so
+                    // that the kernel isn't listed twice.
+                    for other in format.variants().iter().filter(|f| **f != format) {
+                        let other = output_path(stats_dir, layout, &version, *other);
+
+                        if other.is_file() {
+                            fs::remove_file(&other).map_err(|e| {
+                                anyhow!("failed to remove output: {}: {}", other.display(), e)
+                            })?;
+                        }
+                    }
+
+                    if let Some(raw) = &output.meta.raw_tokei {
+                        let compression = match format {
+                            Format::PlainJson => None,
+                            _ => Some(compression),
+                        };
+
+                        let raw_path =
+                            raw_tokei_path(stats_dir, layout, &version, compression.is_some());
+                        write_raw_tokei(&raw_path, raw, compression)?;
+
+                        let other =
+                            raw_tokei_path(stats_dir, layout, &version, compression.is_none());
+
+                        if other.is_file() {
+                            fs::remove_file(&other).map_err(|e| {
+                                anyhow!("failed to remove output: {}: {}", other.display(), e)
+                            })?;
+                        }
+                    }
+
+                    outputs.push(p);
+                }
+
+                Ok(())
+            })();
+
+            stored.map_err(Error::Io)?;
+            Ok(output)
+        });
+
+        let result = result.and_then(|output| {
+            if let Some(path) = discard.get(&version) {
+                info!("discarding: {}", path.display());
+
+                if let Err(e) = kernels::remove_archive(path) {
+                    warn!("{}", e);
+                }
+            }
+
+            analyzed += 1;
+            timings += output.meta.timings;
+
+            // NB: the output has already been stored locally at this point,
+            // so a failure to post it doesn't lose anything.
+            let posted = match post_url {
+                Some(post_url) => runtime
+                    .block_on(post_output(&client, post_url, &output))
+                    .map_err(Error::Post),
+                None => Ok(()),
+            };
+
+            if html_path.is_some() {
+                latest = Some(output);
+            }
+
+            posted
+        });
+
+        match result {
+            Ok(()) => (),
+            Err(e) if keep_going => {
+                warn!("failed to process: {}: {}", version, e);
+                failures.push((version, e));
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    };
+
+    let remaining = tokio::task::block_in_place(|| {
+        analyze_parallel(
+            pending,
+            analysis_parallelism,
+            &interrupted,
+            work_dir,
+            keep_work,
+            &tokei,
+            retries,
+            store,
+        )
+    })?;
 
-        o.flush()
-            .with_context(|| anyhow!("failed to sync: {}", p.display()))?;
+    if remaining > 0 {
+        warn!("interrupted: skipped {} remaining kernel(s)", remaining);
+    }
+
+    if analyzed > 0 {
+        info!(
+            "time spent on {} kernel(s): download {:.2}s, unpack {:.2}s, tokei {:.2}s",
+            analyzed,
+            timings.download.as_secs_f64(),
+            timings.unpack.as_secs_f64(),
+            timings.tokei.as_secs_f64()
+        );
+    }
+
+    if let Some(html_path) = html_path {
+        match &latest {
+            Some(output) => {
+                info!("writing html: {}", html_path.display());
+                write_html(html_path, output)?;
+            }
+            None => warn!(
+                "no kernel was analyzed, not writing: {}",
+                html_path.display()
+            ),
+        }
+    }
+
+    if let Some(summary_path) = summary_path {
+        info!("writing summary: {}", summary_path.display());
+        write_summary(summary_path, &outputs, &tokei.renames, tokei.min_code)?;
+    }
+
+    if let Some(prometheus_path) = prometheus_path {
+        info!("writing metrics: {}", prometheus_path.display());
+        write_prometheus(prometheus_path, &outputs)?;
+    }
+
+    if let Some(manifest_path) = manifest_path {
+        info!("writing manifest: {}", manifest_path.display());
+        write_manifest(manifest_path, stats_dir, &outputs)?;
+    }
+
+    if !failures.is_empty() {
+        eprintln!("{} kernel(s) failed:", failures.len());
+
+        let mut by_kind = BTreeMap::<_, Vec<_>>::new();
+
+        for (version, e) in &failures {
+            by_kind.entry(e.kind()).or_default().push((version, e));
+        }
+
+        for (kind, failures) in by_kind {
+            eprintln!("  {} ({}):", kind, failures.len());
+
+            for (version, e) in failures {
+                eprintln!("    {}: {:#}", version, e);
+            }
+        }
+
+        return Err(anyhow!("{} kernel(s) failed", failures.len()));
+    }
+
+    if interrupted.load(Ordering::SeqCst) {
+        return Err(anyhow!("interrupted"));
     }
 
     Ok(())
 }
+
+/// Install a Ctrl-C handler which sets the returned flag, so that the kernels
+/// currently being analyzed can finish before exiting.
+///
+/// A second Ctrl-C kills tokei, removes the work directories which are in
+/// use and exits immediately.
+fn interrupt_handler() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+
+        warn!(
+            "interrupted: finishing the current kernel(s), press Ctrl-C again to exit immediately"
+        );
+        flag.store(true, Ordering::SeqCst);
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            // NB: tokei runs in its own process group, so it doesn't get the
+            // Ctrl-C and has to be killed before its work directory can be
+            // removed.
+            warn!("interrupted: killing tokei and removing work directories");
+            tokei::kill_process_groups();
+            queue::remove_work_dirs();
+            process::exit(130);
+        }
+    });
+
+    interrupted
+}
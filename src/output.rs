@@ -0,0 +1,387 @@
+//! Reading and writing kernel outputs in the stats directory.
+
+use crate::kernels::major_version;
+use crate::stats::SCHEMA_VERSION;
+use crate::tokei::Tokei;
+use crate::version::Version;
+use crate::Output;
+use anyhow::{anyhow, Context as _, Result};
+use flate2::Compression;
+use log::{info, warn};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The format to write kernel outputs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Gzipped JSON, containing all statistics.
+    Json,
+    /// Uncompressed JSON, containing all statistics.
+    PlainJson,
+    /// CSV with one row of totals per language.
+    Csv,
+}
+
+impl Format {
+    /// Parse the format from its command-line name.
+    pub fn parse(format: &str) -> Result<Format> {
+        match format {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            other => Err(anyhow!("unsupported format: {}", other)),
+        }
+    }
+
+    /// The file extension used for outputs in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json.gz",
+            Format::PlainJson => "json",
+            Format::Csv => "csv",
+        }
+    }
+
+    /// Test if this is one of the json formats.
+    pub fn is_json(self) -> bool {
+        matches!(self, Format::Json | Format::PlainJson)
+    }
+
+    /// Formats whose outputs count as existing outputs for this format, in
+    /// order of preference.
+    pub fn variants(self) -> &'static [Format] {
+        match self {
+            Format::Json => &[Format::Json, Format::PlainJson],
+            Format::PlainJson => &[Format::PlainJson, Format::Json],
+            Format::Csv => &[Format::Csv],
+        }
+    }
+}
+
+/// How outputs are laid out in the stats directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// All outputs directly in the stats directory.
+    Flat,
+    /// Outputs grouped into one directory per major version, like
+    /// `v6.x/linux-v6.6.json.gz`.
+    Nested,
+}
+
+/// Get the path that the output for the given version is written to.
+///
+/// Versions without a major version are always written to the root of the
+/// stats directory.
+pub fn output_path(stats_dir: &Path, layout: Layout, version: &str, format: Format) -> PathBuf {
+    let name = format!("linux-{}.{}", version, format.extension());
+
+    match (layout, major_version(version)) {
+        (Layout::Nested, Some(major)) => stats_dir.join(format!("v{}.x", major)).join(name),
+        _ => stats_dir.join(name),
+    }
+}
+
+/// Get the path that the raw output of tokei for the given version is
+/// written to, next to the output itself.
+pub fn raw_tokei_path(
+    stats_dir: &Path,
+    layout: Layout,
+    version: &str,
+    compressed: bool,
+) -> PathBuf {
+    let p = output_path(stats_dir, layout, version, Format::PlainJson);
+    let mut p = p.with_extension("tokei.json").into_os_string();
+
+    if compressed {
+        p.push(".gz");
+    }
+
+    PathBuf::from(p)
+}
+
+/// Find an existing output for the given version, in any variant of the
+/// given format.
+pub fn existing_output(
+    stats_dir: &Path,
+    layout: Layout,
+    version: &str,
+    format: Format,
+) -> Option<PathBuf> {
+    format
+        .variants()
+        .iter()
+        .map(|f| output_path(stats_dir, layout, version, *f))
+        .find(|p| p.is_file())
+}
+
+/// Write a file atomically.
+///
+/// The contents are written to `<path>.tmp` and synced, before being renamed
+/// into place. So any file present at `path` is always complete.
+pub fn write_atomic<F>(p: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(fs::File) -> Result<fs::File>,
+{
+    let mut tmp = p.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let result = (|| {
+        let o = fs::File::create(&tmp)
+            .map_err(|e| anyhow!("failed to create output file: {}: {}", tmp.display(), e))?;
+
+        let o = write(o)?;
+
+        o.sync_all()
+            .map_err(|e| anyhow!("failed to sync: {}: {}", tmp.display(), e))?;
+
+        fs::rename(&tmp, p).map_err(|e| {
+            anyhow!(
+                "failed to rename: {} -> {}: {}",
+                tmp.display(),
+                p.display(),
+                e
+            )
+        })?;
+
+        Ok(())
+    })();
+
+    if result.is_err() && tmp.is_file() {
+        if let Err(e) = fs::remove_file(&tmp) {
+            warn!("failed to remove temporary file: {}: {}", tmp.display(), e);
+        }
+    }
+
+    result
+}
+
+/// Write the given output as gzipped JSON.
+pub fn write_json(p: &Path, output: &Output, compression: Compression) -> Result<()> {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    write_atomic(p, |o| {
+        let mut o = GzEncoder::new(o, compression);
+
+        serde_json::to_writer(&mut o, output).map_err(|e| anyhow!("failed to serialize: {}", e))?;
+        writeln!(o)?;
+
+        o.finish()
+            .with_context(|| anyhow!("failed to finish: {}", p.display()))
+    })
+}
+
+/// Write the given output as uncompressed JSON.
+pub fn write_plain_json(p: &Path, output: &Output) -> Result<()> {
+    use std::io::Write;
+
+    write_atomic(p, |mut o| {
+        serde_json::to_writer(&mut o, output).map_err(|e| anyhow!("failed to serialize: {}", e))?;
+        writeln!(o)?;
+        Ok(o)
+    })
+}
+
+/// Write the raw output of tokei, optionally gzipped.
+pub fn write_raw_tokei(p: &Path, raw: &str, compression: Option<Compression>) -> Result<()> {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    write_atomic(p, |mut o| match compression {
+        Some(compression) => {
+            let mut o = GzEncoder::new(o, compression);
+            o.write_all(raw.as_bytes())?;
+            o.finish()
+                .with_context(|| anyhow!("failed to finish: {}", p.display()))
+        }
+        None => {
+            o.write_all(raw.as_bytes())?;
+            Ok(o)
+        }
+    })
+}
+
+/// Write the given output as a standalone HTML page.
+pub fn write_html(p: &Path, output: &Output) -> Result<()> {
+    use std::io::Write;
+
+    write_atomic(p, |mut o| {
+        o.write_all(crate::html::render(output).as_bytes())?;
+        Ok(o)
+    })
+}
+
+/// Write the given output as CSV, with one row per language sorted by name.
+pub fn write_csv(p: &Path, output: &Output) -> Result<()> {
+    use std::io::Write;
+
+    write_atomic(p, |o| {
+        let mut o = std::io::BufWriter::new(o);
+
+        let mut languages = output.all.iter().collect::<Vec<_>>();
+        languages.sort_by(|a, b| a.0.cmp(b.0));
+
+        writeln!(
+            o,
+            "language,blanks,code,comments,files,avg_code_per_file,comment_ratio"
+        )?;
+
+        for (language, stats) in languages {
+            writeln!(
+                o,
+                "{},{},{},{},{},{:.2},{:.4}",
+                csv_field(language),
+                stats.blanks,
+                stats.code,
+                stats.comments,
+                stats.files,
+                stats.avg_code_per_file,
+                stats.comment_ratio
+            )?;
+        }
+
+        o.into_inner()
+            .map_err(|e| anyhow!("failed to flush: {}: {}", p.display(), e.error()))
+    })
+}
+
+/// Quote a CSV field if necessary.
+pub fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Read a previously written kernel output.
+pub fn read_output(path: &Path) -> Result<Output> {
+    read_output_with(path, None)
+}
+
+/// Read a previously written kernel output, upgrading it if it was written
+/// with an older schema.
+///
+/// If `migrate` is set, upgraded outputs are also rewritten in place. Gzipped
+/// outputs are rewritten with the given compression.
+pub fn read_output_with(path: &Path, migrate: Option<Compression>) -> Result<Output> {
+    use flate2::read::GzDecoder;
+
+    let f = fs::File::open(path)
+        .map_err(|e| anyhow!("failed to open output: {}: {}", path.display(), e))?;
+
+    // NB: outputs are either gzipped or plain json, depending on the extension.
+    let reader: Box<dyn std::io::Read> = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => Box::new(GzDecoder::new(f)),
+        _ => Box::new(f),
+    };
+
+    let mut output: Output = serde_json::from_reader(reader)
+        .with_context(|| anyhow!("failed to deserialize output: {}", path.display()))?;
+
+    if output.schema_version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "unsupported schema version: {}: {}: written by a newer version of kernelstats",
+            path.display(),
+            output.schema_version
+        ));
+    }
+
+    if let Some(compression) = migrate.filter(|_| output.migrate()) {
+        info!("migrating: {}", path.display());
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => write_json(path, &output, compression)?,
+            _ => write_plain_json(path, &output)?,
+        }
+    }
+
+    Ok(output)
+}
+
+/// Test if the output at the given path was produced by a different version
+/// of tokei than the one in use.
+pub fn is_outdated(path: &Path, tokei: &Tokei) -> Result<bool> {
+    let output = read_output(path)?;
+    Ok(Some(&output.tokei_version) != tokei.version.as_ref())
+}
+
+/// Test if the output at the given path is missing or can't be read, in which
+/// case the kernel needs to be analyzed again.
+///
+/// See [read_output_with] for what `migrate` does.
+pub fn needs_repair(path: &Path, migrate: Option<Compression>) -> bool {
+    if !path.is_file() {
+        return true;
+    }
+
+    match read_output_with(path, migrate) {
+        Ok(_) => false,
+        Err(e) => {
+            warn!("invalid statistics: {}: {:#}", path.display(), e);
+            true
+        }
+    }
+}
+
+/// List the files in the stats directory, including the files in the
+/// directories of the nested layout.
+pub fn output_files(stats_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut queue = vec![stats_dir.to_path_buf()];
+
+    while let Some(dir) = queue.pop() {
+        let entries = fs::read_dir(&dir)
+            .map_err(|e| anyhow!("failed to read directory: {}: {}", dir.display(), e))?;
+
+        for e in entries {
+            let p = e
+                .with_context(|| anyhow!("failed to read entry: {}", dir.display()))?
+                .path();
+
+            // NB: only descend one level, into directories like `v6.x`.
+            if dir == stats_dir && p.is_dir() {
+                let major = p
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(".x"))
+                    .and_then(major_version);
+
+                if major.is_some() {
+                    queue.push(p);
+                }
+
+                continue;
+            }
+
+            files.push(p);
+        }
+    }
+
+    Ok(files)
+}
+
+/// List all outputs in the stats directory, in release order.
+pub fn sorted_outputs(stats_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut outputs = Vec::new();
+
+    for p in output_files(stats_dir)? {
+        let version = match p
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("linux-"))
+            .and_then(|n| {
+                n.strip_suffix(".json.gz")
+                    .or_else(|| n.strip_suffix(".json"))
+            }) {
+            // NB: raw outputs of tokei are stored next to the outputs.
+            Some(version) if !version.ends_with(".tokei") => Version::parse(version),
+            _ => continue,
+        };
+
+        outputs.push((version, p));
+    }
+
+    outputs.sort();
+    Ok(outputs.into_iter().map(|(_, p)| p).collect())
+}
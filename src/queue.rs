@@ -0,0 +1,510 @@
+//! Analyzing a queue of kernels.
+
+use crate::error::Error;
+use crate::git::Git;
+use crate::kernels;
+use crate::stats;
+use crate::tokei::Tokei;
+use crate::{Output, Timings};
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Get the path in the work directory that the kernel with the given version
+/// is unpacked or checked out into.
+///
+/// Unless the directory is kept, its name includes the id of this process, so
+/// that concurrent runs against the same work directory don't collide. Kept
+/// directories have a stable name, so that later runs can reuse them.
+fn work_path(work_dir: &Path, version: &str, keep: bool) -> PathBuf {
+    if keep {
+        work_dir.join(format!("linux-{}", version))
+    } else {
+        work_dir.join(format!("linux-{}-{}", version, process::id()))
+    }
+}
+
+/// A RAM-backed filesystem which is available on all Linux systems.
+#[cfg(target_os = "linux")]
+const MEMORY_DIR: Option<&str> = Some("/dev/shm");
+#[cfg(not(target_os = "linux"))]
+const MEMORY_DIR: Option<&str> = None;
+
+/// Get a work directory in memory with room for at least `size` bytes, if
+/// one is available on this system.
+pub fn memory_work_dir(size: u64) -> Result<Option<PathBuf>> {
+    let root = match MEMORY_DIR.map(Path::new) {
+        Some(root) if root.is_dir() => root,
+        _ => {
+            warn!("no memory-backed filesystem available, using the work directory");
+            return Ok(None);
+        }
+    };
+
+    let available = fs2::available_space(root)
+        .map_err(|e| anyhow!("failed to get free space: {}: {}", root.display(), e))?;
+
+    if available < size {
+        warn!(
+            "only {} bytes available in: {}: using the work directory",
+            available,
+            root.display()
+        );
+        return Ok(None);
+    }
+
+    let dir = root.join(format!("kernelstats-{}", process::id()));
+    fs::create_dir_all(&dir)
+        .map_err(|e| anyhow!("failed to create directory: {}: {}", dir.display(), e))?;
+    Ok(Some(dir))
+}
+
+/// Call the given function, and measure how long it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let value = f();
+    (value, started.elapsed())
+}
+
+/// The repository a guarded work directory is a worktree of.
+struct Worktree {
+    repo: PathBuf,
+    config: Vec<String>,
+}
+
+lazy_static! {
+    /// Work directories which are guarded by a [WorkDir], and the repository
+    /// of those which are worktrees.
+    static ref GUARDED: Mutex<HashMap<PathBuf, Option<Worktree>>> = Mutex::new(HashMap::new());
+}
+
+/// Remove all work directories which are currently guarded.
+///
+/// This is for when the process is about to exit without dropping the
+/// guards, like on a second Ctrl-C.
+pub fn remove_work_dirs() {
+    let guarded = GUARDED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .drain()
+        .collect::<Vec<_>>();
+
+    for (path, git) in guarded {
+        let result = match &git {
+            Some(worktree) => Git::new(&worktree.repo)
+                .with_config(&worktree.config)
+                .remove_worktree(&path),
+            None if path.is_dir() => fs::remove_dir_all(&path)
+                .map_err(|e| anyhow!("failed to remove dir: {}: {}", path.display(), e)),
+            None => Ok(()),
+        };
+
+        if let Err(e) = result {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Guard for an unpacked work directory, which removes it when dropped.
+///
+/// This makes sure that a failed analysis doesn't leave a partially unpacked
+/// tree behind which would be reused by the next run.
+pub struct WorkDir<'a> {
+    path: PathBuf,
+    keep: bool,
+    /// The repository the work directory is a worktree of, if any.
+    git: Option<Git<'a>>,
+}
+
+impl<'a> WorkDir<'a> {
+    /// Guard a directory which isn't a worktree, removing it when dropped.
+    pub fn new(path: PathBuf) -> Self {
+        WorkDir::guard(path, false, None)
+    }
+
+    /// Guard the given directory, and keep track of it unless it's kept so
+    /// that [remove_work_dirs] can remove it.
+    fn guard(path: PathBuf, keep: bool, git: Option<Git<'a>>) -> Self {
+        if !keep {
+            let worktree = git.map(|git| Worktree {
+                repo: git.repo.to_owned(),
+                config: git.config.to_vec(),
+            });
+
+            GUARDED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(path.clone(), worktree);
+        }
+
+        WorkDir { path, keep, git }
+    }
+
+    /// Remove the work directory, reporting any errors.
+    fn remove(mut self) -> Result<()> {
+        if self.keep {
+            return Ok(());
+        }
+
+        self.keep = true;
+        self.remove_now()
+    }
+
+    fn remove_now(&self) -> Result<()> {
+        match self.git {
+            Some(git) => git.remove_worktree(&self.path),
+            None => fs::remove_dir_all(&self.path)
+                .map_err(|e| anyhow!("failed to remove dir: {}: {}", self.path.display(), e)),
+        }
+    }
+}
+
+impl Drop for WorkDir<'_> {
+    fn drop(&mut self) {
+        if !self.keep && self.path.is_dir() {
+            if let Err(e) = self.remove_now() {
+                warn!("{}", e);
+            }
+        }
+
+        GUARDED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.path);
+    }
+}
+
+/// A kernel to build, the path it's
+#[derive(Debug, Clone)]
+pub enum Kernel<'a> {
+    /// A kernel tar.gz that needs to be unpacked.
+    Cached {
+        /// The version of the cached kernel, in `v{major}.{minor}` format.
+        version: String,
+        /// Path to the cached kernel.
+        path: &'a Path,
+        /// The release date of the kernel, if known.
+        released: Option<String>,
+        /// The mirror the archive was fetched from, if it was fetched in this
+        /// run.
+        mirror: Option<&'a str>,
+        /// The name of the source that `mirror` was declared as, if any.
+        source: Option<&'a str>,
+        /// Time spent downloading the archive in this run.
+        download_time: Duration,
+        /// The SHA256 checksum of the archive, if it's already known.
+        /// Otherwise it's computed from the archive.
+        sha256: Option<&'a str>,
+    },
+    /// A git directory tag, or the head of a branch.
+    Git {
+        /// The tag of the kernel.
+        tag: String,
+        /// The reference to check out, which is the tag itself unless this is
+        /// the head of a branch.
+        reference: String,
+        /// The tag of the previous release, if known.
+        prev: Option<String>,
+        /// The git handle for the kernel.
+        git: Git<'a>,
+        /// The remote to fetch the tag from just before it's analyzed, if it
+        /// isn't available locally.
+        remote: Option<&'a str>,
+        /// Reuse a single working tree for all tags, instead of checking out
+        /// each one from scratch.
+        incremental: bool,
+        /// Also compute statistics for only the files which changed since the
+        /// previous release.
+        churn: bool,
+    },
+    /// A kernel tree or archive outside of the cache, like a vendor kernel.
+    Local {
+        /// The tag to store the statistics under.
+        tag: String,
+        /// Path to the source tree, or to an archive of it.
+        path: &'a Path,
+    },
+}
+
+impl<'a> Kernel<'a> {
+    /// Get the version of the kernel.
+    pub fn version(&self) -> &str {
+        match *self {
+            Kernel::Cached { ref version, .. } => version.as_str(),
+            Kernel::Git { ref tag, .. } => tag.as_str(),
+            Kernel::Local { ref tag, .. } => tag.as_str(),
+        }
+    }
+
+    /// Analyze the given kernel.
+    ///
+    /// If `keep_work` is set, unpacked kernels are left in the work directory.
+    ///
+    /// Errors are categorized by what failed, see [Error].
+    pub fn analyze(self, work_dir: &Path, keep_work: bool, tokei: &Tokei) -> Result<Output, Error> {
+        let started = Instant::now();
+        let mut output = self.analyze_tree(work_dir, keep_work, tokei)?;
+
+        output.meta.analysis_secs = started.elapsed().as_secs_f64();
+        Ok(output)
+    }
+
+    fn analyze_tree(
+        self,
+        work_dir: &Path,
+        keep_work: bool,
+        tokei: &Tokei,
+    ) -> Result<Output, Error> {
+        match self {
+            Kernel::Cached {
+                version,
+                path,
+                released,
+                mirror,
+                source,
+                download_time,
+                sha256,
+            } => {
+                let work_dir = work_path(work_dir, &version, keep_work);
+                let guard = WorkDir::guard(work_dir.clone(), keep_work, None);
+
+                let (output_dir, unpack) = timed(|| kernels::unpack_archive(path, &work_dir));
+                let output_dir = output_dir.map_err(Error::Decode)?;
+
+                // NB: fall back to when the archive was last modified.
+                let released = match released {
+                    Some(released) => Some(released),
+                    None => fs::metadata(path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .map(|modified| {
+                            chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339()
+                        }),
+                };
+
+                let (output, tokei_time) =
+                    timed(|| tokei.analyze(version.to_string(), &output_dir));
+                let mut output = output.map_err(Error::Tokei)?;
+                output.released = released;
+                output.meta.timings = Timings {
+                    download: download_time,
+                    unpack,
+                    tokei: tokei_time,
+                };
+                output.meta.mirror = mirror.map(str::to_string);
+                output.meta.source = source.map(str::to_string);
+                output.meta.archive_sha256 = Some(match sha256 {
+                    Some(sha256) => sha256.to_string(),
+                    None => kernels::sha256_file(path).map_err(Error::Io)?,
+                });
+
+                guard.remove().map_err(Error::Io)?;
+                Ok(output)
+            }
+            Kernel::Git {
+                tag,
+                reference,
+                prev,
+                git,
+                remote,
+                incremental,
+                churn,
+            } => {
+                let mut timings = Timings::default();
+
+                if let Some(remote) = remote {
+                    if !git.has_tag(&tag).map_err(Error::Git)? {
+                        info!("fetching {} from: {}", tag, remote);
+                        let (result, download) = timed(|| git.fetch_tag(remote, &tag));
+                        result.map_err(Error::Git)?;
+                        timings.download = download;
+                    }
+                }
+
+                info!("building statistics for release: {}", tag);
+                let commit = git.rev_parse(&reference).map_err(Error::Git)?;
+                let released = git.tag_date(&reference).map_err(Error::Git)?;
+
+                let work_dir = if incremental {
+                    work_dir.join("linux-git")
+                } else {
+                    work_path(work_dir, &tag, keep_work)
+                };
+
+                let started = Instant::now();
+
+                if incremental && work_dir.is_dir() {
+                    let worktree = git.worktree(&work_dir);
+
+                    // NB: only a clean tree is guaranteed to match the tag
+                    // once checked out.
+                    if worktree.is_dirty().map_err(Error::Git)? {
+                        warn!("cleaning dirty worktree: {}", work_dir.display());
+                        worktree.clean().map_err(Error::Git)?;
+                    }
+
+                    worktree.checkout(&reference).map_err(Error::Git)?;
+                } else {
+                    // NB: left behind by an earlier run with `--keep-work`.
+                    if work_dir.is_dir() {
+                        git.remove_worktree(&work_dir).map_err(Error::Git)?;
+                    }
+
+                    if tokei.paths.is_empty() {
+                        git.add_worktree(&reference, &work_dir)
+                            .map_err(Error::Git)?;
+                    } else {
+                        git.add_sparse_worktree(&reference, &work_dir, &tokei.paths)
+                            .map_err(Error::Git)?;
+                    }
+                }
+
+                timings.unpack = started.elapsed();
+
+                let guard = WorkDir::guard(work_dir.clone(), keep_work || incremental, Some(git));
+
+                let (output, tokei_time) = timed(|| tokei.analyze(tag, &work_dir));
+                let mut output = output.map_err(Error::Tokei)?;
+                timings.tokei = tokei_time;
+                output.meta.timings = timings;
+                output.commit = Some(commit);
+                output.released = Some(released.to_rfc3339());
+
+                if let Some(prev) = prev {
+                    // NB: a shallow repository is missing the commits in
+                    // between, so any count would be wrong.
+                    if !git.is_shallow().map_err(Error::Git)? {
+                        output.commits_since_prev =
+                            Some(git.rev_list_count(&prev, &reference).map_err(Error::Git)?);
+                    }
+
+                    if churn {
+                        let changed = git.changed_files(&prev, &reference).map_err(Error::Git)?;
+                        info!("{} file(s) changed since: {}", changed.len(), prev);
+                        output.churn = stats::churn(&output.all, &changed);
+                    }
+                }
+
+                guard.remove().map_err(Error::Git)?;
+                Ok(output)
+            }
+            Kernel::Local { tag, path } => {
+                if path.is_dir() {
+                    let (output, tokei_time) = timed(|| tokei.analyze(tag, path));
+                    let mut output = output.map_err(Error::Tokei)?;
+                    output.meta.timings.tokei = tokei_time;
+                    return Ok(output);
+                }
+
+                let work_dir = work_path(work_dir, &tag, keep_work);
+                let guard = WorkDir::guard(work_dir.clone(), keep_work, None);
+
+                let (output_dir, unpack) = timed(|| kernels::unpack_archive(path, &work_dir));
+                let output_dir = output_dir.map_err(Error::Decode)?;
+                let (output, tokei_time) = timed(|| tokei.analyze(tag, &output_dir));
+                let mut output = output.map_err(Error::Tokei)?;
+                output.meta.timings.unpack = unpack;
+                output.meta.timings.tokei = tokei_time;
+                output.meta.archive_sha256 = Some(kernels::sha256_file(path).map_err(Error::Io)?);
+
+                guard.remove().map_err(Error::Io)?;
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// Analyze the given kernels using `parallelism` threads.
+///
+/// `store` is called on the current thread with the result of each analysis
+/// as it completes. If it fails, no more analyses are started and the error
+/// is returned once the running ones have completed. The same happens if
+/// `interrupted` is set, in which case the number of kernels which were never
+/// analyzed is returned.
+///
+/// A failed analysis is attempted again up to `retries` times.
+#[allow(clippy::too_many_arguments)]
+pub fn analyze_parallel<S>(
+    queue: Vec<Kernel<'_>>,
+    parallelism: usize,
+    interrupted: &AtomicBool,
+    work_dir: &Path,
+    keep_work: bool,
+    tokei: &Tokei,
+    retries: usize,
+    mut store: S,
+) -> Result<usize>
+where
+    S: FnMut(String, Result<Output, Error>) -> Result<()>,
+{
+    use crossbeam_utils::thread;
+    use std::panic;
+    use std::sync::mpsc;
+
+    let queue = Mutex::new(queue.into_iter());
+    let stopped = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let (tx, rx) = mpsc::channel();
+
+        for _ in 0..parallelism {
+            let tx = tx.clone();
+            let queue = &queue;
+            let stopped = &stopped;
+
+            s.spawn(move |_| loop {
+                if interrupted.load(Ordering::SeqCst) || stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let q = match queue.lock().unwrap_or_else(|e| e.into_inner()).next() {
+                    Some(q) => q,
+                    None => break,
+                };
+
+                info!("process: {:?}", q);
+                let version = q.version().to_string();
+                let mut attempt = 0;
+
+                let output = loop {
+                    match q.clone().analyze(work_dir, keep_work, tokei) {
+                        Err(e) if attempt < retries && !interrupted.load(Ordering::SeqCst) => {
+                            attempt += 1;
+                            warn!(
+                                "failed to analyze: {}: {:#}: retrying ({}/{})",
+                                version, e, attempt, retries
+                            );
+                        }
+                        output => break output,
+                    }
+                };
+
+                if tx.send((version, output)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        drop(tx);
+
+        for (version, output) in rx {
+            if let Err(e) = store(version, output) {
+                stopped.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    })
+    .unwrap_or_else(|e| panic::resume_unwind(e))?;
+
+    let remaining = queue.lock().unwrap_or_else(|e| e.into_inner()).len();
+    Ok(remaining)
+}
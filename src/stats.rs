@@ -0,0 +1,646 @@
+//! Statistics produced by analyzing a directory.
+
+use serde::{Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{hash_map, BTreeMap, HashMap, HashSet};
+use std::ops;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Statistics for a single file, as listed under `stats` for each language in
+/// the output of tokei.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stat {
+    /// Number of blank lines.
+    pub blanks: u64,
+    /// Number of lines of code.
+    pub code: u64,
+    /// Number of comment lines.
+    pub comments: u64,
+    /// Total number of lines.
+    pub lines: u64,
+    /// The path of the file, relative to the analyzed directory.
+    pub name: PathBuf,
+}
+
+/// Serialize a map with its keys in sorted order, so that identical outputs
+/// serialize identically.
+fn sorted<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+/// Serialize a map of maps with all keys in sorted order, see [sorted].
+fn sorted_nested<K, V, S>(map: &HashMap<K, HashMap<K, V>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(
+        map.iter()
+            .map(|(k, v)| (k, v.iter().collect::<BTreeMap<_, _>>()))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+/// Statistics for a single language.
+///
+/// Code embedded in files of this language, like JavaScript in HTML or code
+/// blocks in Markdown, is reported by tokei as `children`. Children are kept
+/// separate and are *not* included in the totals of the language, so that
+/// summing the totals of all languages never counts a line twice.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LanguageStats {
+    /// Number of blank lines.
+    pub blanks: u64,
+    /// Number of lines of code.
+    pub code: u64,
+    /// Number of comment lines.
+    pub comments: u64,
+    /// Total number of lines.
+    pub lines: u64,
+    /// Sum of blank, code, and comment lines.
+    #[serde(default)]
+    pub total: u64,
+    /// Number of files.
+    #[serde(default)]
+    pub files: usize,
+    /// Average number of lines of code per file, or zero if there are no
+    /// files.
+    #[serde(default)]
+    pub avg_code_per_file: f64,
+    /// Lines of comments per line of code, or zero if there's no code.
+    #[serde(default)]
+    pub comment_ratio: f64,
+    /// Statistics for each file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stats: Vec<Stat>,
+    /// Statistics for each file of embedded code, by embedded language.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted"
+    )]
+    pub children: HashMap<String, Vec<Stat>>,
+}
+
+impl ops::AddAssign for LanguageStats {
+    fn add_assign(&mut self, other: LanguageStats) {
+        self.blanks += other.blanks;
+        self.code += other.code;
+        self.comments += other.comments;
+        self.lines += other.lines;
+        self.total += other.total;
+        self.files += other.files;
+        self.stats.extend(other.stats);
+        self.compute_derived();
+
+        for (language, stats) in other.children {
+            self.children.entry(language).or_default().extend(stats);
+        }
+
+        self.sort_stats();
+    }
+}
+
+impl LanguageStats {
+    /// Compute the `total` and `files` fields from the other statistics, and
+    /// sort the per-file statistics by name.
+    ///
+    /// The number of files is only recomputed if per-file statistics are
+    /// available. Children are not included.
+    pub fn compute_totals(&mut self) {
+        self.total = self.blanks + self.code + self.comments;
+
+        if !self.stats.is_empty() {
+            self.files = self.stats.len();
+        }
+
+        self.compute_derived();
+        self.sort_stats();
+    }
+
+    /// Add the statistics of a single file to the totals, without keeping
+    /// per-file statistics.
+    fn add_file(&mut self, stat: &Stat) {
+        self.blanks += stat.blanks;
+        self.code += stat.code;
+        self.comments += stat.comments;
+        self.lines += stat.lines;
+        self.total += stat.blanks + stat.code + stat.comments;
+        self.files += 1;
+    }
+
+    /// Sort the per-file statistics by name, since tokei reports them in no
+    /// particular order.
+    fn sort_stats(&mut self) {
+        self.stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for stats in self.children.values_mut() {
+            stats.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+    }
+
+    /// Compute the `avg_code_per_file` and `comment_ratio` fields from the
+    /// other statistics.
+    fn compute_derived(&mut self) {
+        self.avg_code_per_file = match self.files {
+            0 => 0.0,
+            files => self.code as f64 / files as f64,
+        };
+
+        self.comment_ratio = match self.code {
+            0 => 0.0,
+            code => self.comments as f64 / code as f64,
+        };
+    }
+
+    /// Sum up the statistics of the code embedded in this language, by
+    /// embedded language.
+    pub fn children_totals(&self) -> HashMap<String, LanguageStats> {
+        let mut totals = HashMap::new();
+
+        for (language, stats) in &self.children {
+            let entry: &mut LanguageStats = totals.entry(language.clone()).or_default();
+
+            for stat in stats {
+                entry.add_file(stat);
+            }
+        }
+
+        for stats in totals.values_mut() {
+            stats.compute_derived();
+        }
+
+        totals
+    }
+}
+
+/// Language names which have changed between versions of tokei, and the name
+/// they're normalized to.
+pub const DEFAULT_RENAMES: &[(&str, &str)] = &[
+    ("BASH", "Bash"),
+    ("DeviceTree", "Device Tree"),
+    ("Sh", "Shell"),
+    ("Text", "Plain Text"),
+    ("reStructuredText", "ReStructuredText"),
+];
+
+/// Get the default language renames, see [DEFAULT_RENAMES].
+pub fn default_renames() -> HashMap<String, String> {
+    DEFAULT_RENAMES
+        .iter()
+        .map(|&(from, to)| (from.to_string(), to.to_string()))
+        .collect()
+}
+
+/// Rename languages according to the given renames, so that outputs from
+/// different versions of tokei use the same language names.
+///
+/// Languages which end up with the same name are merged, and embedded
+/// languages are renamed as well.
+pub fn normalize_languages(
+    all: HashMap<String, LanguageStats>,
+    renames: &HashMap<String, String>,
+) -> HashMap<String, LanguageStats> {
+    let rename = |language: String| match renames.get(&language) {
+        Some(to) => to.clone(),
+        None => language,
+    };
+
+    let mut normalized = HashMap::with_capacity(all.len());
+
+    for (language, mut stats) in all {
+        let mut children = HashMap::<String, Vec<Stat>>::new();
+
+        for (language, stats) in stats.children.drain() {
+            children.entry(rename(language)).or_default().extend(stats);
+        }
+
+        stats.children = children;
+
+        match normalized.entry(rename(language)) {
+            hash_map::Entry::Occupied(mut o) => *o.get_mut() += stats,
+            hash_map::Entry::Vacant(v) => {
+                v.insert(stats);
+            }
+        }
+    }
+
+    normalized
+}
+
+/// Group per-file statistics by subsystem, which is the top-level directory
+/// that each file belongs to, like `drivers` or `fs`.
+///
+/// Files in the root of the tree are grouped under `.`.
+pub fn by_subsystem(
+    all: &HashMap<String, LanguageStats>,
+) -> HashMap<String, HashMap<String, LanguageStats>> {
+    use std::path::Component;
+
+    let mut subsystems = HashMap::<String, HashMap<String, LanguageStats>>::new();
+
+    for (language, stats) in all {
+        for stat in &stats.stats {
+            let mut components = stat
+                .name
+                .components()
+                .filter(|c| matches!(c, Component::Normal(..)));
+
+            let subsystem = match (components.next(), components.next()) {
+                (Some(first), Some(_)) => first.as_os_str().to_string_lossy().into_owned(),
+                _ => String::from("."),
+            };
+
+            let entry = subsystems
+                .entry(subsystem)
+                .or_default()
+                .entry(language.clone())
+                .or_default();
+
+            entry.add_file(stat);
+        }
+    }
+
+    for stats in subsystems.values_mut().flat_map(|s| s.values_mut()) {
+        stats.compute_derived();
+    }
+
+    subsystems
+}
+
+/// Sum up the per-file statistics of only the given files, by language.
+///
+/// Files are given relative to the analyzed directory, like the output of
+/// `git diff --name-only`. Embedded languages are not included.
+pub fn churn(
+    all: &HashMap<String, LanguageStats>,
+    files: &HashSet<PathBuf>,
+) -> HashMap<String, LanguageStats> {
+    use std::path::Component;
+
+    let mut churn = HashMap::<String, LanguageStats>::new();
+
+    for (language, stats) in all {
+        for stat in &stats.stats {
+            // NB: tokei reports files like `./fs/super.c`.
+            let name = stat
+                .name
+                .components()
+                .filter(|c| matches!(c, Component::Normal(..)))
+                .collect::<PathBuf>();
+
+            if !files.contains(&name) {
+                continue;
+            }
+
+            let entry = churn.entry(language.clone()).or_default();
+            entry.add_file(stat);
+        }
+    }
+
+    for stats in churn.values_mut() {
+        stats.compute_derived();
+    }
+
+    churn
+}
+
+/// Sum up the per-file statistics of all languages by file extension, only
+/// keeping the given extensions, which are compared case-insensitively.
+///
+/// This doesn't depend on how tokei classifies files, only on their names.
+pub fn by_extension(
+    all: &HashMap<String, LanguageStats>,
+    extensions: &[String],
+) -> HashMap<String, LanguageStats> {
+    let mut by_extension = HashMap::<String, LanguageStats>::new();
+
+    for stat in all.values().flat_map(|s| s.stats.iter()) {
+        let extension = match stat.name.extension().and_then(|e| e.to_str()) {
+            Some(extension) => extension,
+            None => continue,
+        };
+
+        let extension = match extensions
+            .iter()
+            .find(|e| e.eq_ignore_ascii_case(extension))
+        {
+            Some(extension) => extension,
+            None => continue,
+        };
+
+        by_extension
+            .entry(extension.clone())
+            .or_default()
+            .add_file(stat);
+    }
+
+    for stats in by_extension.values_mut() {
+        stats.compute_derived();
+    }
+
+    by_extension
+}
+
+/// A curated selection of files which is reported on separately from the
+/// whole tree, like all Rust code.
+#[derive(Debug)]
+pub struct Report {
+    /// The name of the report, which it's selected and stored by.
+    pub name: &'static str,
+    /// Directories whose files are included, relative to the analyzed
+    /// directory.
+    pub dirs: &'static [&'static str],
+    /// File extensions which are included anywhere in the tree.
+    pub extensions: &'static [&'static str],
+}
+
+/// The reports which are available, see [Report].
+pub const REPORTS: &[Report] = &[Report {
+    name: "rust",
+    dirs: &["rust"],
+    extensions: &["rs"],
+}];
+
+impl Report {
+    /// Find the report with the given name.
+    pub fn find(name: &str) -> Option<&'static Report> {
+        REPORTS.iter().find(|r| r.name == name)
+    }
+
+    /// Sum up the per-file statistics of the files included in the report,
+    /// by language. Embedded languages are not included.
+    pub fn apply(&self, all: &HashMap<String, LanguageStats>) -> HashMap<String, LanguageStats> {
+        use std::path::Component;
+
+        let mut report = HashMap::<String, LanguageStats>::new();
+
+        for (language, stats) in all {
+            for stat in &stats.stats {
+                let mut components = stat
+                    .name
+                    .components()
+                    .filter(|c| matches!(c, Component::Normal(..)));
+
+                let in_dir = match components.next() {
+                    Some(first) => self.dirs.iter().any(|d| first.as_os_str() == *d),
+                    None => false,
+                };
+
+                let has_extension = match stat.name.extension().and_then(|e| e.to_str()) {
+                    Some(extension) => self.extensions.contains(&extension),
+                    None => false,
+                };
+
+                if !in_dir && !has_extension {
+                    continue;
+                }
+
+                report.entry(language.clone()).or_default().add_file(stat);
+            }
+        }
+
+        for stats in report.values_mut() {
+            stats.compute_derived();
+        }
+
+        report
+    }
+}
+
+/// The language that languages dropped by [MinCode] are summed up under.
+pub const OTHER_LANGUAGE: &str = "Other";
+
+/// Drop languages with too few lines of code, like configuration snippets
+/// and one-off scripts.
+#[derive(Debug, Clone, Copy)]
+pub struct MinCode {
+    /// The number of lines of code a language needs to be kept.
+    pub code: u64,
+    /// Sum up the dropped languages under [OTHER_LANGUAGE], instead of
+    /// discarding them.
+    pub other: bool,
+}
+
+impl MinCode {
+    /// Drop the languages with fewer lines of code than the threshold.
+    pub fn apply(self, all: &mut HashMap<String, LanguageStats>) {
+        let dropped = all
+            .iter()
+            .filter(|(_, s)| s.code < self.code)
+            .map(|(language, _)| language.clone())
+            .collect::<Vec<_>>();
+
+        let mut other = LanguageStats::default();
+
+        for language in &dropped {
+            if let Some(stats) = all.remove(language) {
+                other += stats;
+            }
+        }
+
+        // NB: the bucket is kept even if it's below the threshold itself, so
+        // that the totals of the output are unchanged.
+        if self.other && !dropped.is_empty() {
+            *all.entry(OTHER_LANGUAGE.to_string()).or_default() += other;
+        }
+    }
+}
+
+/// A single file in the list of the largest files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopFile {
+    /// The path of the file, relative to the analyzed directory.
+    pub name: PathBuf,
+    /// The language of the file.
+    pub language: String,
+    /// Number of lines of code.
+    pub code: u64,
+}
+
+/// Find the `n` files with the most lines of code across all languages,
+/// largest first.
+pub fn top_files(all: &HashMap<String, LanguageStats>, n: usize) -> Vec<TopFile> {
+    let mut files = all
+        .iter()
+        .flat_map(|(language, stats)| {
+            stats.stats.iter().map(move |stat| TopFile {
+                name: stat.name.clone(),
+                language: language.clone(),
+                code: stat.code,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // NB: ties are broken by name to keep the output stable.
+    files.sort_by(|a, b| b.code.cmp(&a.code).then_with(|| a.name.cmp(&b.name)));
+    files.truncate(n);
+    files
+}
+
+/// How an output was produced, so that it can be audited independently.
+///
+/// The analyzed commit and the version of tokei are recorded in
+/// [Output::commit] and [Output::tokei_version].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OutputMeta {
+    /// The mirror that the archive was downloaded from, if it was downloaded.
+    #[serde(default)]
+    pub mirror: Option<String>,
+    /// The name of the source in the list of releases that the mirror was
+    /// declared as, if any.
+    #[serde(default)]
+    pub source: Option<String>,
+    /// The SHA256 of the analyzed archive, if analyzed from an archive.
+    #[serde(default)]
+    pub archive_sha256: Option<String>,
+    /// Wall-clock time spent analyzing the kernel, in seconds.
+    #[serde(default)]
+    pub analysis_secs: f64,
+    /// Time spent in each phase of producing the output. Only available for
+    /// outputs produced in this run.
+    #[serde(skip)]
+    pub timings: Timings,
+    /// The unmodified output of tokei, if requested. Only available for
+    /// outputs produced in this run.
+    #[serde(skip)]
+    pub raw_tokei: Option<String>,
+}
+
+/// Time spent in each phase of producing an output.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timings {
+    /// Downloading or fetching the kernel, which is zero if it was already
+    /// available.
+    pub download: Duration,
+    /// Unpacking or checking out the kernel.
+    pub unpack: Duration,
+    /// Running tokei.
+    pub tokei: Duration,
+}
+
+impl ops::AddAssign for Timings {
+    fn add_assign(&mut self, other: Timings) {
+        self.download += other.download;
+        self.unpack += other.unpack;
+        self.tokei += other.tokei;
+    }
+}
+
+/// The version of the schema of [Output], which is bumped whenever fields are
+/// added that older outputs don't have.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// The output of analyzing a single kernel.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Output {
+    /// The version of the schema the output was written with, see
+    /// [SCHEMA_VERSION]. Outputs written before it was added are version 0.
+    #[serde(default)]
+    pub schema_version: u32,
+    /// The tag that we build for.
+    pub tag: String,
+    /// The commit that the tag resolved to, if analyzed from git.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// When the kernel was released, in ISO-8601 format.
+    #[serde(default)]
+    pub released: Option<String>,
+    /// The version of tokei which produced the statistics.
+    #[serde(default)]
+    pub tokei_version: String,
+    /// The number of commits since the previous release, if analyzed from git
+    /// with the full history.
+    #[serde(default)]
+    pub commits_since_prev: Option<u64>,
+    /// How the output was produced.
+    #[serde(default)]
+    pub meta: OutputMeta,
+    /// Statistics for all languages.
+    #[serde(serialize_with = "sorted")]
+    pub all: HashMap<String, LanguageStats>,
+    /// Statistics for all languages, grouped by subsystem.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted_nested"
+    )]
+    pub by_subsystem: HashMap<String, HashMap<String, LanguageStats>>,
+    /// Statistics for only the files which changed since the previous
+    /// release, by language, if requested.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted"
+    )]
+    pub churn: HashMap<String, LanguageStats>,
+    /// Statistics for only the requested file extensions, regardless of
+    /// language.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted"
+    )]
+    pub by_extension: HashMap<String, LanguageStats>,
+    /// Statistics for the requested reports, by report and language, see
+    /// [Report].
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "sorted_nested"
+    )]
+    pub reports: HashMap<String, HashMap<String, LanguageStats>>,
+    /// The files with the most lines of code, if requested.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub top_files: Vec<TopFile>,
+}
+
+impl Output {
+    /// Construct a new kernel output.
+    pub fn new(tag: String) -> Output {
+        Output {
+            schema_version: SCHEMA_VERSION,
+            tag,
+            commit: None,
+            released: None,
+            tokei_version: String::new(),
+            commits_since_prev: None,
+            meta: OutputMeta::default(),
+            all: Default::default(),
+            by_subsystem: Default::default(),
+            churn: Default::default(),
+            by_extension: Default::default(),
+            reports: Default::default(),
+            top_files: Vec::new(),
+        }
+    }
+
+    /// Upgrade an output written with an older schema to the current one, by
+    /// deriving the fields it's missing from the ones it has.
+    ///
+    /// Returns `true` if the output was upgraded.
+    pub fn migrate(&mut self) -> bool {
+        if self.schema_version >= SCHEMA_VERSION {
+            return false;
+        }
+
+        // NB: totals and derived metrics were added over time.
+        for stats in self.all.values_mut() {
+            stats.compute_totals();
+        }
+
+        if self.by_subsystem.is_empty() {
+            self.by_subsystem = by_subsystem(&self.all);
+        }
+
+        self.schema_version = SCHEMA_VERSION;
+        true
+    }
+}
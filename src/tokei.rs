@@ -0,0 +1,568 @@
+//! Running tokei to calculate code statistics.
+
+use crate::git;
+use crate::stats::{
+    by_extension, by_subsystem, default_renames, normalize_languages, top_files, LanguageStats,
+    MinCode, Output, Report,
+};
+use anyhow::{anyhow, Context as _, Result};
+use ignore::overrides::{Override, OverrideBuilder};
+use lazy_static::lazy_static;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long tokei is allowed to run by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    /// The process groups of tokei processes which are running in their own
+    /// process group, see [Tokei::process_group].
+    static ref PROCESS_GROUPS: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+/// Kill every tokei process which is running in its own process group.
+///
+/// These don't get the Ctrl-C from the terminal, so they have to be killed
+/// explicitly if the process exits without waiting for them.
+pub fn kill_process_groups() {
+    #[cfg(unix)]
+    {
+        let groups = PROCESS_GROUPS.lock().unwrap_or_else(|e| e.into_inner());
+
+        for &pgid in groups.iter() {
+            // SAFETY: killpg has no memory safety requirements.
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Keeps track of the process group of a running tokei process, for
+/// [kill_process_groups].
+struct ProcessGroup(Option<u32>);
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            PROCESS_GROUPS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&pgid);
+        }
+    }
+}
+
+/// How tokei is invoked.
+#[derive(Debug)]
+pub struct Tokei {
+    /// The tokei program to run.
+    pub bin: PathBuf,
+    /// Additional paths to exclude.
+    pub excludes: Vec<String>,
+    /// How long tokei is allowed to run before it's killed.
+    pub timeout: Duration,
+    /// Extra arguments to pass to tokei.
+    pub args: Vec<String>,
+    /// The version of tokei, as detected by [Tokei::check].
+    pub version: Option<String>,
+    /// Run tokei in its own process group, so that it isn't interrupted by
+    /// Ctrl-C in the terminal. Only has an effect on unix.
+    pub process_group: bool,
+    /// List this many of the largest files in the output of [Tokei::analyze].
+    pub top_files: Option<usize>,
+    /// Only keep statistics for these languages in the output of
+    /// [Tokei::analyze], compared case-insensitively. Empty keeps all
+    /// languages.
+    pub languages: Vec<String>,
+    /// Only analyze these directories, relative to the analyzed directory.
+    /// Empty analyzes everything.
+    pub paths: Vec<String>,
+    /// Also sum up statistics by these file extensions in the output of
+    /// [Tokei::analyze], regardless of language. Empty skips it.
+    pub extensions: Vec<String>,
+    /// Languages to rename in the output of [Tokei::analyze], see
+    /// [normalize_languages].
+    pub renames: HashMap<String, String>,
+    /// Keep the unmodified output of tokei in the output of
+    /// [Tokei::analyze], see [OutputMeta::raw_tokei].
+    ///
+    /// [OutputMeta::raw_tokei]: crate::stats::OutputMeta::raw_tokei
+    pub keep_raw: bool,
+    /// Drop languages with too few lines of code from the output of
+    /// [Tokei::analyze], after filtering languages.
+    pub min_code: Option<MinCode>,
+    /// Reports to include in the output of [Tokei::analyze], regardless of
+    /// language.
+    pub reports: Vec<&'static Report>,
+    /// Also analyze the files and directories that symbolic links resolve
+    /// to, as if they were where the links are. tokei skips symbolic links
+    /// otherwise.
+    pub follow_symlinks: bool,
+    /// Fail if no language has any lines of code, which is what happens when
+    /// a whole kernel tree is analyzed with a version of tokei whose output
+    /// doesn't parse the way we expect.
+    pub require_code: bool,
+}
+
+impl Default for Tokei {
+    fn default() -> Tokei {
+        Tokei {
+            bin: PathBuf::from("tokei"),
+            excludes: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+            args: Vec::new(),
+            version: None,
+            process_group: false,
+            top_files: None,
+            languages: Vec::new(),
+            paths: Vec::new(),
+            extensions: Vec::new(),
+            renames: default_renames(),
+            keep_raw: false,
+            min_code: None,
+            reports: Vec::new(),
+            follow_symlinks: false,
+            require_code: false,
+        }
+    }
+}
+
+impl Tokei {
+    /// Check that the given extra argument doesn't change the output format
+    /// of tokei, which would break parsing its output.
+    pub fn validate_arg(arg: &str) -> Result<()> {
+        if arg.starts_with("--output") || (arg.starts_with("-o") && !arg.starts_with("--")) {
+            return Err(anyhow!(
+                "tokei argument would override the output format: {}",
+                arg
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that tokei can be run, and detect its version.
+    pub fn check(&mut self) -> Result<()> {
+        let out = process::Command::new(&self.bin)
+            .arg("--version")
+            .output()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => anyhow!(
+                    "tokei is not installed: {}: install it with `cargo install tokei`",
+                    self.bin.display()
+                ),
+                _ => anyhow!("failed to run tokei: {}: {}", self.bin.display(), e),
+            })?;
+
+        if !out.status.success() {
+            return Err(anyhow!(
+                "failed to run tokei: {}: {}",
+                self.bin.display(),
+                out.status
+            ));
+        }
+
+        // NB: the output is like `tokei 12.1.2 compiled with ...`.
+        let stdout = str::from_utf8(&out.stdout)?;
+        let version = stdout
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| anyhow!("unexpected tokei version: {}", stdout.trim()))?;
+
+        self.version = Some(version.to_string());
+        Ok(())
+    }
+
+    /// Analyze the given directory, producing an output with the given tag.
+    pub fn analyze(&self, tag: String, dir: &Path) -> Result<Output> {
+        let mut output = Output::new(tag);
+        output.tokei_version = self.version.clone().unwrap_or_default();
+        let raw = self
+            .execute(dir, &self.paths())
+            .with_context(|| anyhow!("running tokei for: {}", output.tag))?;
+        let mut all = self
+            .parse(&raw)
+            .with_context(|| anyhow!("running tokei for: {}", output.tag))?;
+
+        if self.follow_symlinks {
+            self.analyze_symlinks(dir, &mut all)
+                .with_context(|| anyhow!("following symlinks for: {}", output.tag))?;
+        }
+
+        output.all = normalize_languages(all, &self.renames);
+
+        if self.keep_raw {
+            output.meta.raw_tokei = Some(raw);
+        }
+
+        // NB: computed before filtering languages, since it doesn't depend on
+        // them.
+        if !self.extensions.is_empty() {
+            output.by_extension = by_extension(&output.all, &self.extensions);
+        }
+
+        for report in &self.reports {
+            output
+                .reports
+                .insert(report.name.to_string(), report.apply(&output.all));
+        }
+
+        if !self.languages.is_empty() {
+            let languages = &self.languages;
+
+            output
+                .all
+                .retain(|language, _| languages.iter().any(|l| l.eq_ignore_ascii_case(language)));
+        }
+
+        if let Some(min_code) = self.min_code {
+            min_code.apply(&mut output.all);
+        }
+
+        output.by_subsystem = by_subsystem(&output.all);
+
+        if let Some(n) = self.top_files {
+            output.top_files = top_files(&output.all, n);
+        }
+
+        Ok(output)
+    }
+
+    /// The paths to analyze, relative to the analyzed directory.
+    fn paths(&self) -> Vec<&OsStr> {
+        self.paths.iter().map(OsStr::new).collect()
+    }
+
+    /// Analyze the targets of the symbolic links in the given directory, and
+    /// add them to `all` as if they were where the links are.
+    ///
+    /// Links which are excluded, or are in excluded directories, are
+    /// skipped. A target which several links resolve to is only counted once.
+    fn analyze_symlinks(&self, dir: &Path, all: &mut HashMap<String, LanguageStats>) -> Result<()> {
+        let excludes = self.exclude_matcher(dir)?;
+        let mut links = find_symlinks(dir)?;
+
+        links.retain(|l| {
+            // NB: tokei doesn't descend into excluded directories, so a link
+            // is excluded if any of the directories it's in are.
+            let excluded = l
+                .link
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .any(|p| {
+                    let is_dir = p != l.link || l.target.is_dir();
+                    excludes.matched(p, is_dir).is_ignore()
+                });
+
+            let selected =
+                self.paths.is_empty() || self.paths.iter().any(|p| l.link.starts_with(p));
+            selected && !excluded
+        });
+
+        let mut seen = HashSet::new();
+        links.retain(|l| seen.insert(l.target.clone()));
+
+        if links.is_empty() {
+            return Ok(());
+        }
+
+        let targets = links
+            .iter()
+            .map(|l| l.target.as_os_str())
+            .collect::<Vec<_>>();
+        let stdout = self.execute(dir, &targets)?;
+
+        for (language, stats) in relink(self.parse_languages(&stdout)?, &links) {
+            *all.entry(language).or_default() += stats;
+        }
+
+        Ok(())
+    }
+
+    /// Build a matcher for the excludes in the given directory.
+    ///
+    /// tokei passes excludes as overrides to the `ignore` crate, which makes
+    /// them globs with the same semantics as in a `.gitignore`.
+    fn exclude_matcher(&self, dir: &Path) -> Result<Override> {
+        let mut builder = OverrideBuilder::new(dir);
+
+        for exclude in &self.excludes {
+            builder
+                .add(&format!("!{}", exclude))
+                .map_err(|e| anyhow!("bad exclude: {}: {}", exclude, e))?;
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// Call tokei on the given paths, relative to the given directory, and
+    /// get its unmodified output.
+    fn execute(&self, dir: &Path, paths: &[&OsStr]) -> Result<String> {
+        let mut command = process::Command::new(&self.bin);
+        command
+            .current_dir(dir)
+            .args(&["-o", "json", "--exclude", ".git"]);
+
+        for exclude in &self.excludes {
+            command.arg("--exclude").arg(exclude);
+        }
+
+        command.args(&self.args);
+        command.args(paths);
+        git::log_command(&command);
+
+        let out = self.output(command)?;
+
+        if !out.status.success() {
+            let out = str::from_utf8(&out.stderr)?;
+            return Err(anyhow!("tokei error: {}", out).into());
+        }
+
+        Ok(String::from_utf8(out.stdout)?)
+    }
+
+    /// Parse the output of tokei into statistics, which has to count some
+    /// lines of code if [Tokei::require_code] is set.
+    fn parse(&self, stdout: &str) -> Result<HashMap<String, LanguageStats>> {
+        let all = self.parse_languages(stdout)?;
+
+        if self.require_code && all.values().all(|s| s.code == 0) {
+            return Err(self.unexpected("no language has any lines of code"));
+        }
+
+        Ok(all)
+    }
+
+    /// Parse the output of tokei into statistics.
+    fn parse_languages(&self, stdout: &str) -> Result<HashMap<String, LanguageStats>> {
+        let value: serde_json::Value = serde_json::from_str(stdout)?;
+        self.validate(&value)?;
+
+        let mut all: HashMap<String, LanguageStats> =
+            serde_json::from_value(value).map_err(|e| self.unexpected(e))?;
+
+        for stats in all.values_mut() {
+            stats.compute_totals();
+        }
+
+        Ok(all)
+    }
+
+    /// Check that the output of tokei has the structure we expect, so that a
+    /// change in its format doesn't silently produce empty statistics.
+    fn validate(&self, value: &serde_json::Value) -> Result<()> {
+        let languages = value
+            .as_object()
+            .ok_or_else(|| self.unexpected("expected an object of languages"))?;
+
+        for (language, stats) in languages {
+            for key in &["blanks", "code", "comments"] {
+                if !matches!(stats.get(key), Some(v) if v.is_u64()) {
+                    return Err(self.unexpected(format!(
+                        "language `{}` is missing the number `{}`",
+                        language, key
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Construct an error for output of tokei in an unexpected format.
+    fn unexpected(&self, reason: impl std::fmt::Display) -> anyhow::Error {
+        anyhow!(
+            "tokei output format unexpected, check the tokei version ({}): {}",
+            self.version.as_deref().unwrap_or("unknown"),
+            reason
+        )
+    }
+
+    /// Run the given command to completion, killing it if it runs for longer
+    /// than the configured timeout.
+    fn output(&self, mut command: process::Command) -> Result<process::Output> {
+        use std::io::Read as _;
+        use std::thread;
+        use std::time::Instant;
+
+        #[cfg(unix)]
+        {
+            if self.process_group {
+                use std::os::unix::process::CommandExt as _;
+
+                // SAFETY: setpgid is async-signal-safe.
+                unsafe {
+                    command.pre_exec(|| {
+                        if libc::setpgid(0, 0) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+
+                        Ok(())
+                    });
+                }
+            }
+        }
+
+        let mut child = command
+            .stdout(process::Stdio::piped())
+            .stderr(process::Stdio::piped())
+            .spawn()?;
+
+        // NB: the process group has the same id as the process which leads it.
+        let _group = if cfg!(unix) && self.process_group {
+            PROCESS_GROUPS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(child.id());
+            ProcessGroup(Some(child.id()))
+        } else {
+            ProcessGroup(None)
+        };
+
+        // NB: read outputs in the background so that tokei doesn't block on a
+        // full pipe while we're waiting for it.
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("missing stdout"))?;
+        let mut stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| anyhow!("missing stderr"))?;
+
+        let stdout = thread::spawn(move || {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        let stderr = thread::spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        });
+
+        let deadline = Instant::now() + self.timeout;
+
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(anyhow!("tokei timed out after {}s", self.timeout.as_secs()));
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        };
+
+        let stdout = stdout
+            .join()
+            .map_err(|_| anyhow!("failed to read stdout"))??;
+        let stderr = stderr
+            .join()
+            .map_err(|_| anyhow!("failed to read stderr"))??;
+
+        Ok(process::Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// A symbolic link in an analyzed directory, see [find_symlinks].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symlink {
+    /// The path of the link, relative to the analyzed directory.
+    pub link: PathBuf,
+    /// The canonical path that the link resolves to.
+    pub target: PathBuf,
+}
+
+/// Find the symbolic links in the given directory which resolve to a file or
+/// a directory, sorted by path.
+///
+/// Broken links and the `.git` directory are skipped, and directories which
+/// are linked to aren't searched for more links.
+pub fn find_symlinks(dir: &Path) -> Result<Vec<Symlink>> {
+    let mut links = Vec::new();
+    let mut queue = vec![PathBuf::new()];
+
+    while let Some(relative) = queue.pop() {
+        let path = dir.join(&relative);
+        let entries = fs::read_dir(&path)
+            .map_err(|e| anyhow!("failed to read directory: {}: {}", path.display(), e))?;
+
+        for e in entries {
+            let e = e.with_context(|| anyhow!("failed to read entry: {}", path.display()))?;
+            let link = relative.join(e.file_name());
+
+            let file_type = e
+                .file_type()
+                .map_err(|e| anyhow!("failed to read file type: {}: {}", link.display(), e))?;
+
+            if file_type.is_dir() {
+                if link != Path::new(".git") {
+                    queue.push(link);
+                }
+
+                continue;
+            }
+
+            if !file_type.is_symlink() {
+                continue;
+            }
+
+            if let Ok(target) = fs::canonicalize(e.path()) {
+                links.push(Symlink { link, target });
+            }
+        }
+    }
+
+    links.sort_by(|a, b| a.link.cmp(&b.link));
+    Ok(links)
+}
+
+/// Rename the per-file statistics of files which were analyzed through the
+/// targets of the given links, so that they're named like `./{link}/...`.
+pub fn relink(
+    mut all: HashMap<String, LanguageStats>,
+    links: &[Symlink],
+) -> HashMap<String, LanguageStats> {
+    let rename = |name: &mut PathBuf| {
+        for l in links {
+            if let Ok(rest) = name.strip_prefix(&l.target) {
+                let mut renamed = Path::new(".").join(&l.link);
+
+                // NB: joining an empty path would add a trailing separator.
+                if !rest.as_os_str().is_empty() {
+                    renamed.push(rest);
+                }
+
+                *name = renamed;
+                return;
+            }
+        }
+    };
+
+    for stats in all.values_mut() {
+        for stat in &mut stats.stats {
+            rename(&mut stat.name);
+        }
+
+        for stat in stats.children.values_mut().flatten() {
+            rename(&mut stat.name);
+        }
+    }
+
+    all
+}
@@ -0,0 +1,99 @@
+//! Semantic-ish versions as used by kernel tags, like `v2.6.39` or `v4.9-rc1`.
+
+use std::cmp;
+use std::fmt;
+
+/// A parsed kernel version.
+///
+/// Versions compare numerically component by component, so that `v2.6.39`
+/// orders before `v3.0`. Release candidates order immediately before the
+/// release they belong to.
+#[derive(Debug, Clone)]
+pub struct Version {
+    /// Numeric components of the version, like `[2, 6, 39]`.
+    pub parts: Vec<u32>,
+    /// The release candidate, if this is one.
+    pub rc: Option<u32>,
+}
+
+impl Version {
+    /// Parse a version, with or without the leading `v`.
+    ///
+    /// Returns `None` if the version isn't in a recognized format.
+    pub fn parse(version: &str) -> Option<Version> {
+        let version = version.strip_prefix('v').unwrap_or(version);
+
+        let (version, rc) = match version.find("-rc") {
+            Some(index) => {
+                let rc = version[index + 3..].parse().ok()?;
+                (&version[..index], Some(rc))
+            }
+            None => (version, None),
+        };
+
+        let mut parts = Vec::new();
+
+        for part in version.split('.') {
+            parts.push(part.parse().ok()?);
+        }
+
+        Some(Version { parts, rc })
+    }
+}
+
+impl cmp::PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl cmp::Eq for Version {}
+
+impl cmp::Ord for Version {
+    fn cmp(&self, other: &Version) -> cmp::Ordering {
+        let len = usize::max(self.parts.len(), other.parts.len());
+
+        for index in 0..len {
+            let a = self.parts.get(index).copied().unwrap_or_default();
+            let b = other.parts.get(index).copied().unwrap_or_default();
+
+            match a.cmp(&b) {
+                cmp::Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+
+        match (self.rc, other.rc) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => cmp::Ordering::Less,
+            (None, Some(_)) => cmp::Ordering::Greater,
+            (None, None) => cmp::Ordering::Equal,
+        }
+    }
+}
+
+impl cmp::PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "v")?;
+
+        for (index, part) in self.parts.iter().enumerate() {
+            if index > 0 {
+                write!(fmt, ".")?;
+            }
+
+            part.fmt(fmt)?;
+        }
+
+        if let Some(rc) = self.rc {
+            write!(fmt, "-rc{}", rc)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+//! Helpers shared between the integration tests.
+
+use std::fs;
+use std::path::PathBuf;
+
+/// Get an empty scratch directory which is unique to the given test.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kernelstats-{}-{}", name, std::process::id()));
+
+    if dir.is_dir() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,122 @@
+use kernelstats::kernels::{self, Resume};
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_MODIFIED_SINCE,
+    IF_RANGE, RANGE,
+};
+use reqwest::StatusCode;
+
+/// Get headers with a single `Content-Range`.
+fn content_range(range: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_RANGE, HeaderValue::from_str(range).unwrap());
+    headers
+}
+
+#[test]
+fn request_without_resume() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        IF_MODIFIED_SINCE,
+        HeaderValue::from_static("Sun, 06 Nov 1994 08:49:37 GMT"),
+    );
+
+    let request = kernels::resume_request(&headers, None, Some("\"abc\"")).unwrap();
+    assert_eq!(request, headers);
+}
+
+#[test]
+fn request_with_resume() {
+    let request = kernels::resume_request(&HeaderMap::new(), Some(100), Some("\"abc\"\n")).unwrap();
+    assert_eq!(request[RANGE], "bytes=100-");
+    assert_eq!(request[IF_RANGE], "\"abc\"");
+
+    let request = kernels::resume_request(&HeaderMap::new(), Some(100), None).unwrap();
+    assert_eq!(request[RANGE], "bytes=100-");
+    assert!(!request.contains_key(IF_RANGE));
+
+    // NB: an etag which isn't a valid header is ignored.
+    let request = kernels::resume_request(&HeaderMap::new(), Some(100), Some("a\u{0}b")).unwrap();
+    assert!(!request.contains_key(IF_RANGE));
+}
+
+#[test]
+fn response_without_resume() {
+    let headers = content_range("bytes 100-199/200");
+
+    for status in &[
+        StatusCode::OK,
+        StatusCode::PARTIAL_CONTENT,
+        StatusCode::RANGE_NOT_SATISFIABLE,
+    ] {
+        let resume = kernels::resume_response(None, *status, &headers).unwrap();
+        assert_eq!(resume, Resume::Start);
+    }
+}
+
+#[test]
+fn response_with_resume() {
+    let headers = content_range("bytes 100-199/200");
+
+    let resume = kernels::resume_response(Some(100), StatusCode::PARTIAL_CONTENT, &headers);
+    assert_eq!(resume.unwrap(), Resume::From(100));
+
+    let resume = kernels::resume_response(Some(100), StatusCode::OK, &HeaderMap::new());
+    assert_eq!(resume.unwrap(), Resume::Start);
+
+    let resume = kernels::resume_response(
+        Some(100),
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        &HeaderMap::new(),
+    );
+    assert_eq!(resume.unwrap(), Resume::Restart);
+}
+
+#[test]
+fn response_with_unexpected_range() {
+    let headers = content_range("bytes 0-199/200");
+    assert!(kernels::resume_response(Some(100), StatusCode::PARTIAL_CONTENT, &headers).is_err());
+
+    let headers = HeaderMap::new();
+    assert!(kernels::resume_response(Some(100), StatusCode::PARTIAL_CONTENT, &headers).is_err());
+}
+
+#[test]
+fn html_is_detected() {
+    assert!(kernels::looks_like_html(b"<!DOCTYPE html><html>"));
+    assert!(kernels::looks_like_html(b"<HTML><HEAD>"));
+    assert!(kernels::looks_like_html(b" \r\n\t<html>"));
+    assert!(kernels::looks_like_html(b"\xef\xbb\xbf<!doctype html>"));
+    assert!(kernels::looks_like_html(b"\xef\xbb\xbf\n<html>"));
+
+    assert!(!kernels::looks_like_html(b"\x1f\x8b\x08\x00"));
+    assert!(!kernels::looks_like_html(b"BZh91AY&SY"));
+    assert!(!kernels::looks_like_html(b""));
+}
+
+#[test]
+fn served_html_is_rejected() {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-gzip"));
+    assert!(kernels::check_served(&headers, None).is_ok());
+
+    for content_type in &["text/html", "text/html; charset=utf-8", "Text/HTML"] {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        assert!(kernels::check_served(&headers, None).is_err());
+        assert!(kernels::check_served(&headers, Some(100)).is_err());
+    }
+}
+
+#[test]
+fn served_too_small_is_rejected() {
+    let mut headers = HeaderMap::new();
+    assert!(kernels::check_served(&headers, None).is_ok());
+
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("1023"));
+    assert!(kernels::check_served(&headers, None).is_err());
+
+    // NB: the remainder of a resumed download can be small.
+    assert!(kernels::check_served(&headers, Some(100)).is_ok());
+
+    headers.insert(CONTENT_LENGTH, HeaderValue::from_static("1024"));
+    assert!(kernels::check_served(&headers, None).is_ok());
+}
@@ -0,0 +1,278 @@
+use kernelstats::stats::{self, normalize_languages, MinCode};
+use kernelstats::{LanguageStats, Output};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// Parse the fixture with embedded languages, like tokei would report it.
+///
+/// The fixture is in the flat format that [LanguageStats] parses, where the
+/// files of a language are listed under `stats` like tokei did before 12, and
+/// the files of each embedded language are listed under `children` the same
+/// way. tokei 12 nests per-file statistics under `reports` instead.
+fn nested() -> HashMap<String, LanguageStats> {
+    let mut all: HashMap<String, LanguageStats> =
+        serde_json::from_str(include_str!("fixtures/nested.json")).unwrap();
+
+    for stats in all.values_mut() {
+        stats.compute_totals();
+    }
+
+    all
+}
+
+#[test]
+fn children_are_parsed() {
+    let all = nested();
+    let markdown = &all["Markdown"];
+
+    assert_eq!(markdown.children.len(), 2);
+    assert_eq!(markdown.children["Rust"].len(), 1);
+    assert_eq!(markdown.children["Rust"][0].code, 6);
+    assert_eq!(markdown.children["C"][0].comments, 1);
+    assert!(all["Rust"].children.is_empty());
+}
+
+#[test]
+fn children_are_not_in_parent_totals() {
+    let all = nested();
+    let markdown = &all["Markdown"];
+
+    assert_eq!(markdown.code, 0);
+    assert_eq!(markdown.total, 14);
+    assert_eq!(markdown.files, 1);
+    assert_eq!(all["Rust"].code, 20);
+}
+
+#[test]
+fn children_totals() {
+    let all = nested();
+    let children = all["Markdown"].children_totals();
+
+    assert_eq!(children["Rust"].code, 6);
+    assert_eq!(children["Rust"].total, 7);
+    assert_eq!(children["Rust"].files, 1);
+    assert_eq!(children["C"].code, 3);
+}
+
+#[test]
+fn merging_keeps_children_separate() {
+    let mut total = LanguageStats::default();
+
+    for (_, stats) in nested() {
+        total += stats;
+    }
+
+    assert_eq!(total.code, 20);
+    assert_eq!(total.comments, 13);
+    assert_eq!(total.files, 2);
+    assert_eq!(total.children["Rust"].len(), 1);
+    assert_eq!(total.children["C"].len(), 1);
+}
+
+#[test]
+fn children_round_trip() {
+    let all = nested();
+    let json = serde_json::to_string(&all).unwrap();
+    let parsed: HashMap<String, LanguageStats> = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(parsed["Markdown"].children["Rust"][0].code, 6);
+    assert!(!json.contains("\"children\":{}"));
+}
+
+#[test]
+fn average_code_per_file() {
+    let all = nested();
+
+    assert_eq!(all["Rust"].avg_code_per_file, 20.0);
+    assert_eq!(all["Markdown"].avg_code_per_file, 0.0);
+    assert_eq!(LanguageStats::default().avg_code_per_file, 0.0);
+
+    let mut total = LanguageStats::default();
+
+    for (_, stats) in nested() {
+        total += stats;
+    }
+
+    assert_eq!(total.avg_code_per_file, 10.0);
+}
+
+#[test]
+fn comment_ratio() {
+    let all = nested();
+
+    assert_eq!(all["Rust"].comment_ratio, 3.0 / 20.0);
+    assert_eq!(all["Markdown"].comment_ratio, 0.0);
+
+    let mut total = LanguageStats::default();
+
+    for (_, stats) in nested() {
+        total += stats;
+    }
+
+    assert_eq!(total.comment_ratio, 13.0 / 20.0);
+}
+
+#[test]
+fn churn_only_counts_changed_files() {
+    let all = nested();
+    let changed = vec![PathBuf::from("rust/kernel/lib.rs")]
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+    let churn = stats::churn(&all, &changed);
+
+    assert_eq!(churn.len(), 1);
+    assert_eq!(churn["Rust"].code, 20);
+    assert_eq!(churn["Rust"].files, 1);
+    assert!(stats::churn(&all, &HashSet::new()).is_empty());
+}
+
+#[test]
+fn stats_are_sorted_by_name() {
+    let mut total = LanguageStats::default();
+
+    for (_, stats) in nested() {
+        total += stats;
+    }
+
+    let names = total
+        .stats
+        .iter()
+        .map(|s| s.name.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            PathBuf::from("./Documentation/index.md"),
+            PathBuf::from("./rust/kernel/lib.rs")
+        ]
+    );
+}
+
+#[test]
+fn outputs_serialize_identically() {
+    let serialize = || {
+        let mut output = Output::new(String::from("v1.0"));
+        output.all = nested();
+        output.by_subsystem = stats::by_subsystem(&output.all);
+        serde_json::to_string(&output).unwrap()
+    };
+
+    let json = serialize();
+
+    for _ in 0..8 {
+        assert_eq!(json, serialize());
+    }
+}
+
+#[test]
+fn renamed_languages_are_merged() {
+    let mut renames = stats::default_renames();
+    renames.insert(String::from("Markdown"), String::from("Rust"));
+    renames.insert(String::from("C"), String::from("C Code"));
+
+    let all = normalize_languages(nested(), &renames);
+
+    assert_eq!(all.len(), 1);
+    assert_eq!(all["Rust"].code, 20);
+    assert_eq!(all["Rust"].files, 2);
+    assert_eq!(all["Rust"].children["C Code"][0].code, 3);
+    assert!(!all["Rust"].children.contains_key("C"));
+}
+
+#[test]
+fn old_outputs_are_migrated() {
+    let mut output: Output = serde_json::from_str(
+        r#"{"tag": "v1.0", "tokei_version": "12.0.0", "all": {"C": {"blanks": 1, "code": 4, "comments": 2, "lines": 7}}}"#,
+    )
+    .unwrap();
+
+    assert_eq!(output.schema_version, 0);
+    assert!(output.migrate());
+    assert_eq!(output.schema_version, stats::SCHEMA_VERSION);
+    assert_eq!(output.all["C"].total, 7);
+    assert_eq!(output.all["C"].comment_ratio, 0.5);
+    assert!(!output.migrate());
+}
+
+#[test]
+fn by_extension_ignores_languages() {
+    let all = nested();
+    let extensions = vec![String::from("rs"), String::from("MD"), String::from("c")];
+
+    let by_extension = stats::by_extension(&all, &extensions);
+
+    assert_eq!(by_extension.len(), 2);
+    assert_eq!(by_extension["rs"].code, 20);
+    assert_eq!(by_extension["rs"].files, 1);
+    assert_eq!(by_extension["MD"].comments, 10);
+    assert!(!by_extension.contains_key("c"));
+}
+
+#[test]
+fn min_code_drops_small_languages() {
+    let mut all = nested();
+    MinCode {
+        code: 5,
+        other: false,
+    }
+    .apply(&mut all);
+
+    assert_eq!(all.len(), 1);
+    assert!(all.contains_key("Rust"));
+
+    let mut all = nested();
+    MinCode {
+        code: 5,
+        other: true,
+    }
+    .apply(&mut all);
+
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[stats::OTHER_LANGUAGE].total, 14);
+    assert_eq!(all[stats::OTHER_LANGUAGE].files, 1);
+    assert_eq!(all["Rust"].code, 20);
+}
+
+#[test]
+fn rust_report_includes_rust_dir_and_files() {
+    let mut all = nested();
+
+    let c: LanguageStats = serde_json::from_str(
+        r#"{"blanks": 0, "code": 4, "comments": 0, "lines": 4, "stats": [
+            {"blanks": 0, "code": 4, "comments": 0, "lines": 4, "name": "./rust/helpers.c"},
+            {"blanks": 0, "code": 9, "comments": 0, "lines": 9, "name": "./init/main.c"}
+        ]}"#,
+    )
+    .unwrap();
+    all.insert(String::from("C"), c);
+
+    let report = stats::Report::find("rust").unwrap().apply(&all);
+
+    assert_eq!(report.len(), 2);
+    assert_eq!(report["Rust"].code, 20);
+    assert_eq!(report["C"].code, 4);
+    assert_eq!(report["C"].files, 1);
+}
+
+#[test]
+fn languages_serialize_alphabetically() {
+    let mut output = Output::new(String::from("v1.0"));
+
+    for language in &["Rust", "Assembly", "C", "Shell", "Markdown", "C Header"] {
+        output
+            .all
+            .insert(language.to_string(), LanguageStats::default());
+    }
+
+    let json = serde_json::to_string(&output).unwrap();
+
+    let positions = ["Assembly", "C", "C Header", "Markdown", "Rust", "Shell"]
+        .iter()
+        .map(|l| json.find(&format!("\"{}\":", l)).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut sorted = positions.clone();
+    sorted.sort();
+    assert_eq!(positions, sorted);
+}
@@ -0,0 +1,124 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::scratch;
+use kernelstats::tokei::{self, Tokei};
+use std::fs;
+use std::os::unix::fs::{symlink, PermissionsExt as _};
+use std::path::{Path, PathBuf};
+
+/// A stand-in for tokei which counts every line of the C files it finds as
+/// code. Like tokei, it doesn't follow symbolic links.
+///
+/// It prints the flat per-file `stats` format of tokei before 12, which is
+/// what [LanguageStats] parses, so it reports itself as tokei 11.
+///
+/// [LanguageStats]: kernelstats::LanguageStats
+const FAKE_TOKEI: &str = r#"#!/bin/sh
+[ "$1" = "--version" ] && { echo "tokei 11.2.1"; exit 0; }
+paths=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -o|--exclude) shift 2;;
+        *) paths="$paths $1"; shift;;
+    esac
+done
+[ -z "$paths" ] && paths=.
+total=0
+stats=""
+for f in $(find $paths -type f -name '*.c' | sort); do
+    n=$(wc -l < "$f")
+    total=$((total + n))
+    stats="$stats${stats:+,}{\"blanks\":0,\"code\":$n,\"comments\":0,\"lines\":$n,\"name\":\"$f\"}"
+done
+printf '{"C":{"blanks":0,"code":%d,"comments":0,"lines":%d,"stats":[%s]}}\n' $total $total "$stats"
+"#;
+
+/// Set up a tree with a symbolic link to a file and one to a directory, and
+/// a fake tokei to analyze it with.
+fn tree(name: &str) -> (PathBuf, Tokei) {
+    let dir = scratch(name);
+    let bin = dir.join("tokei");
+    fs::write(&bin, FAKE_TOKEI).unwrap();
+    fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let root = dir.join("linux");
+    fs::create_dir_all(root.join("drivers")).unwrap();
+    fs::write(root.join("init.c"), "a\nb\n").unwrap();
+    fs::write(root.join("drivers/a.c"), "a\nb\nc\n").unwrap();
+
+    let external = dir.join("external");
+    fs::create_dir_all(&external).unwrap();
+    fs::write(external.join("b.c"), "a\nb\nc\nd\n").unwrap();
+
+    symlink(root.join("init.c"), root.join("linked.c")).unwrap();
+    symlink(&external, root.join("drivers/external")).unwrap();
+    symlink(dir.join("missing.c"), root.join("broken.c")).unwrap();
+
+    let tokei = Tokei {
+        bin,
+        ..Tokei::default()
+    };
+
+    (root, tokei)
+}
+
+#[test]
+fn symlinks_are_found() {
+    let (root, _) = tree("find-symlinks");
+    let links = tokei::find_symlinks(&root).unwrap();
+
+    let links = links.iter().map(|l| l.link.as_path()).collect::<Vec<_>>();
+    assert_eq!(
+        links,
+        vec![Path::new("drivers/external"), Path::new("linked.c")]
+    );
+}
+
+#[test]
+fn symlinks_are_skipped_by_default() {
+    let (root, tokei) = tree("skip-symlinks");
+    let all = tokei.analyze(String::from("linux"), &root).unwrap().all;
+
+    assert_eq!(all["C"].code, 5);
+    assert_eq!(all["C"].files, 2);
+}
+
+#[test]
+fn symlinks_are_followed() {
+    let (root, mut tokei) = tree("follow-symlinks");
+    tokei.follow_symlinks = true;
+    let all = tokei.analyze(String::from("linux"), &root).unwrap().all;
+
+    assert_eq!(all["C"].code, 11);
+    assert_eq!(all["C"].files, 4);
+
+    let names = all["C"]
+        .stats
+        .iter()
+        .map(|s| s.name.as_path())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec![
+            Path::new("./drivers/a.c"),
+            Path::new("./drivers/external/b.c"),
+            Path::new("./init.c"),
+            Path::new("./linked.c"),
+        ]
+    );
+}
+
+#[test]
+fn symlinks_are_excluded_by_glob() {
+    let (root, mut tokei) = tree("exclude-symlinks");
+    tokei.follow_symlinks = true;
+    tokei.excludes = vec![String::from("drivers/*")];
+    let all = tokei.analyze(String::from("linux"), &root).unwrap().all;
+
+    // NB: the fake tokei ignores excludes, so only the linked directory is
+    // left out.
+    assert_eq!(all["C"].code, 7);
+    assert_eq!(all["C"].files, 3);
+}
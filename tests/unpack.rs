@@ -0,0 +1,115 @@
+mod common;
+
+use common::scratch;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use kernelstats::kernels;
+use std::fs;
+use std::path::Path;
+
+/// Write a tar.gz archive with the given files and contents.
+fn archive(path: &Path, files: &[(&str, &str)]) {
+    let f = fs::File::create(path).unwrap();
+    let mut builder = tar::Builder::new(GzEncoder::new(f, Compression::default()));
+
+    for (name, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+
+    builder.into_inner().unwrap().finish().unwrap();
+}
+
+#[test]
+fn unpack_single_directory() {
+    let dir = scratch("single");
+    let path = dir.join("linux-1.0.tar.gz");
+    archive(
+        &path,
+        &[
+            ("linux/Makefile", "all:\n"),
+            ("linux/init/main.c", "int main;\n"),
+        ],
+    );
+
+    let work_dir = dir.join("work");
+    let root = kernels::unpack_archive(&path, &work_dir).unwrap();
+    assert_eq!(root, work_dir.join("linux"));
+    assert!(root.join("init/main.c").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unpack_top_level_files() {
+    let dir = scratch("top-level");
+    let path = dir.join("linux-0.01.tar.gz");
+    archive(
+        &path,
+        &[("Makefile", "all:\n"), ("init/main.c", "int main;\n")],
+    );
+
+    let work_dir = dir.join("work");
+    let root = kernels::unpack_archive(&path, &work_dir).unwrap();
+    assert_eq!(root, work_dir);
+    assert!(root.join("Makefile").is_file());
+
+    // NB: already unpacked, so the archive isn't needed anymore.
+    fs::remove_file(&path).unwrap();
+    assert_eq!(kernels::unpack_archive(&path, &work_dir).unwrap(), work_dir);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unpack_uncompressed() {
+    let dir = scratch("uncompressed");
+    let path = dir.join("linux-0.01.tar");
+
+    let mut builder = tar::Builder::new(fs::File::create(&path).unwrap());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "linux/Makefile", &b"all:\n"[..])
+        .unwrap();
+    builder.into_inner().unwrap();
+
+    let work_dir = dir.join("work");
+    let root = kernels::unpack_archive(&path, &work_dir).unwrap();
+    assert!(root.join("Makefile").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unpack_bzip2() {
+    use bzip2::write::BzEncoder;
+
+    let dir = scratch("bzip2");
+    let path = dir.join("linux-2.6.39.tar.bz2");
+
+    let f = fs::File::create(&path).unwrap();
+    let mut builder = tar::Builder::new(BzEncoder::new(f, bzip2::Compression::default()));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, "linux-2.6.39/Makefile", &b"all:\n"[..])
+        .unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+
+    let work_dir = dir.join("work");
+    let root = kernels::unpack_archive(&path, &work_dir).unwrap();
+    assert_eq!(root, work_dir.join("linux-2.6.39"));
+    assert!(root.join("Makefile").is_file());
+
+    fs::remove_dir_all(&dir).unwrap();
+}